@@ -0,0 +1,563 @@
+use rubato::{
+    Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::CODEC_TYPE_NULL;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::probe::Hint;
+
+pub const WHISPER_SAMPLE_RATE: u32 = 16000;
+
+// 無音の判定に使うフレームの長さ。16kHzで480サンプルなので、区間の境目はミリ秒に割り切れる
+const VAD_FRAME_MS: usize = 30;
+// 発話区間の前後に残す余白
+const VAD_PADDING_MS: usize = 210;
+// 下から1割のフレームの音量を雑音とみなし、その何倍を超えたら発話とするか
+const VAD_NOISE_RATIO: f32 = 3.;
+const VAD_MIN_RMS: f32 = 0.003;
+
+#[derive(Debug)]
+pub enum AudioError {
+    Open,
+    Unsupported,
+    NoTrack,
+    Codec,
+    Decode,
+    Resample,
+    SampleRate(u32),
+    Channel(usize),
+    WavFormat(hound::SampleFormat, u16),
+    Track(usize),
+    Write,
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::Open => write!(f, "指定された音声ファイルを開けませんでした"),
+            AudioError::Unsupported => write!(
+                f,
+                "指定された音声ファイルは対応していない形式か、破損しています"
+            ),
+            AudioError::NoTrack => {
+                write!(
+                    f,
+                    "指定された音声ファイルには対応しているトラックがありません"
+                )
+            }
+            AudioError::Codec => write!(
+                f,
+                "指定された音声ファイルのコーデックは対応していません（DRMで保護されたファイルは読み込めません）"
+            ),
+            AudioError::Decode => write!(
+                f,
+                "音声のデコードに失敗しました。ファイルが破損している可能性があります"
+            ),
+            AudioError::Resample => write!(f, "音声のリサンプリングに失敗しました"),
+            AudioError::SampleRate(rate) => write!(
+                f,
+                "サンプリングレート{}Hzの音声は対応していません（{}Hz以上が必要です）",
+                rate, WHISPER_SAMPLE_RATE
+            ),
+            AudioError::WavFormat(format, bits) => write!(
+                f,
+                "{}ビット{}形式のWAVファイルは対応していません",
+                bits,
+                match format {
+                    hound::SampleFormat::Float => "浮動小数点",
+                    hound::SampleFormat::Int => "整数",
+                }
+            ),
+            AudioError::Track(track) => {
+                write!(f, "指定された音声トラック({})がファイルにありません", track)
+            }
+            AudioError::Write => write!(f, "映像から取り出した音声の書き出しに失敗しました"),
+            AudioError::Channel(channel) => {
+                write!(
+                    f,
+                    "指定されたチャンネル({})が音声ファイルにありません",
+                    channel
+                )
+            }
+        }
+    }
+}
+
+pub trait AudioDecoder {
+    fn decode_to_f32_mono(
+        &self,
+        path: &Path,
+        target_sample_rate: u32,
+    ) -> Result<Vec<f32>, AudioError>;
+}
+
+// channelがNoneのときは全チャンネルの平均をとる
+pub struct WavDecoder {
+    channel: Option<usize>,
+}
+
+// trackは音声トラックだけを数えた番号
+pub struct SymphoniaDecoder {
+    channel: Option<usize>,
+    track: usize,
+}
+
+// 拡張子ではなくファイルの先頭バイトで判定する
+pub fn decoder_for(path: &Path, channel: Option<usize>, track: usize) -> Box<dyn AudioDecoder> {
+    if is_wav(path) {
+        Box::new(WavDecoder { channel })
+    } else {
+        Box::new(SymphoniaDecoder { channel, track })
+    }
+}
+
+pub fn decode_to_f32_mono_16k(
+    path: &Path,
+    channel: Option<usize>,
+    track: usize,
+) -> Result<Vec<f32>, AudioError> {
+    decoder_for(path, channel, track).decode_to_f32_mono(path, WHISPER_SAMPLE_RATE)
+}
+
+// 映像ファイルの音声トラックを16kHzモノラルのWAVとして書き出す
+pub fn extract_audio_track(path: &Path, path_out: &Path, track: usize) -> Result<(), AudioError> {
+    let samples = SymphoniaDecoder {
+        channel: None,
+        track,
+    }
+    .decode_to_f32_mono(path, WHISPER_SAMPLE_RATE)?;
+    let mut writer = hound::WavWriter::create(
+        path_out,
+        hound::WavSpec {
+            channels: 1,
+            sample_rate: WHISPER_SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        },
+    )
+    .map_err(|_| AudioError::Write)?;
+    for sample in samples {
+        writer
+            .write_sample((sample.clamp(-1., 1.) * i16::MAX as f32) as i16)
+            .map_err(|_| AudioError::Write)?;
+    }
+    writer.finalize().map_err(|_| AudioError::Write)
+}
+
+pub fn is_video_container(path: &Path) -> bool {
+    let mut header = [0u8; 12];
+    if fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .is_err()
+    {
+        return false;
+    }
+    match header {
+        // Matroska / WebM
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => true,
+        // MP4 / MOV (音声だけのm4aは除く)
+        [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] => {
+            !matches!(&brand, b"M4A " | b"M4B " | b"M4P ")
+        }
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'A', b'V', b'I', b' '] => true,
+        _ => false,
+    }
+}
+
+// 破棄されるときにファイルを削除する
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(path: PathBuf) -> TempFile {
+        TempFile(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+pub fn duration_ms(samples: &[f32]) -> i64 {
+    samples.len() as i64 * 1000 / WHISPER_SAMPLE_RATE as i64
+}
+
+// デコードせずにWAVのヘッダーだけから長さを求める
+pub fn wav_duration_ms(path: &Path) -> Result<u64, AudioError> {
+    let reader = hound::WavReader::open(path).map_err(|_| AudioError::Open)?;
+    let sample_rate = reader.spec().sample_rate as u64;
+    if sample_rate == 0 {
+        return Err(AudioError::SampleRate(0));
+    }
+    Ok(reader.duration() as u64 * 1000 / sample_rate)
+}
+
+// フレームごとの音量から発話らしい区間を探し、サンプル位置の範囲で返す。
+// min_silence_msより短い無音は発話の続きとして扱う
+pub fn speech_regions(samples: &[f32], min_silence_ms: usize) -> Vec<Range<usize>> {
+    let frame_len = WHISPER_SAMPLE_RATE as usize / 1000 * VAD_FRAME_MS;
+    let rms: Vec<f32> = samples
+        .chunks(frame_len)
+        .map(|frame| (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt())
+        .collect();
+    if rms.is_empty() {
+        return Vec::new();
+    }
+    let mut sorted = rms.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let threshold = (sorted[sorted.len() / 10] * VAD_NOISE_RATIO).max(VAD_MIN_RMS);
+
+    let min_silence_frames = min_silence_ms.div_ceil(VAD_FRAME_MS);
+    let padding_frames = VAD_PADDING_MS.div_ceil(VAD_FRAME_MS);
+    let mut regions: Vec<Range<usize>> = Vec::new();
+    for (i, _) in rms.iter().enumerate().filter(|(_, &rms)| rms > threshold) {
+        let start = i.saturating_sub(padding_frames);
+        let end = (i + 1 + padding_frames).min(rms.len());
+        match regions.last_mut() {
+            Some(last) if start <= last.end + min_silence_frames => last.end = end,
+            _ => regions.push(start..end),
+        }
+    }
+    regions
+        .into_iter()
+        .map(|frames| frames.start * frame_len..(frames.end * frame_len).min(samples.len()))
+        .collect()
+}
+
+// 変換せずにそのままwhisperへ渡せる16kHzモノラルのWAVかどうか
+pub fn is_whisper_wav(path: &Path) -> bool {
+    hound::WavReader::open(path).is_ok_and(|reader| {
+        let spec = reader.spec();
+        spec.sample_rate == WHISPER_SAMPLE_RATE && spec.channels == 1
+    })
+}
+
+pub fn to_mono_16k(
+    samples: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+) -> Result<Vec<f32>, AudioError> {
+    resample(
+        downmix(samples, channels, None)?,
+        sample_rate,
+        WHISPER_SAMPLE_RATE,
+    )
+}
+
+fn is_wav(path: &Path) -> bool {
+    let mut header = [0u8; 12];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .is_ok()
+        && &header[0..4] == b"RIFF"
+        && &header[8..12] == b"WAVE"
+}
+
+impl AudioDecoder for WavDecoder {
+    fn decode_to_f32_mono(
+        &self,
+        path: &Path,
+        target_sample_rate: u32,
+    ) -> Result<Vec<f32>, AudioError> {
+        let mut reader = hound::WavReader::open(path).map_err(|_| AudioError::Open)?;
+        let spec = reader.spec();
+        // 16kHz未満はリサンプリングしても使えないので、サンプルを読む前に弾く。
+        // 16kHz以上やステレオはdownmix/resampleで16kHzモノラルに揃える
+        if spec.sample_rate < target_sample_rate {
+            return Err(AudioError::SampleRate(spec.sample_rate));
+        }
+        // 整数は符号付きに揃えて読まれるので最大振幅で割って[-1.0, 1.0]にする
+        let samples: Vec<f32> = match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Float, 32) => reader.samples::<f32>().collect::<Result<_, _>>(),
+            (hound::SampleFormat::Int, bits @ (8 | 16 | 24 | 32)) => {
+                let scale = (1i64 << (bits - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|s| s as f32 / scale))
+                    .collect::<Result<_, _>>()
+            }
+            (format, bits) => return Err(AudioError::WavFormat(format, bits)),
+        }
+        .map_err(|_| AudioError::Decode)?;
+        resample(
+            downmix(samples, spec.channels as usize, self.channel)?,
+            spec.sample_rate,
+            target_sample_rate,
+        )
+    }
+}
+
+impl AudioDecoder for SymphoniaDecoder {
+    fn decode_to_f32_mono(
+        &self,
+        path: &Path,
+        target_sample_rate: u32,
+    ) -> Result<Vec<f32>, AudioError> {
+        let src = fs::File::open(path).map_err(|_| AudioError::Open)?;
+        let mss = MediaSourceStream::new(Box::new(src), Default::default());
+        let probed = symphonia::default::get_probe()
+            .format(&Hint::new(), mss, &Default::default(), &Default::default())
+            .map_err(|_| AudioError::Unsupported)?;
+        let mut format = probed.format;
+        let mut tracks = format
+            .tracks()
+            .iter()
+            .filter(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .peekable();
+        if tracks.peek().is_none() {
+            return Err(AudioError::NoTrack);
+        }
+        let track = tracks
+            .nth(self.track)
+            .ok_or(AudioError::Track(self.track))?;
+        let track_id = track.id;
+        let mut sample_rate = track.codec_params.sample_rate.unwrap_or_default();
+        let mut channels = track.codec_params.channels.map_or(1, |c| c.count());
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &Default::default())
+            .map_err(|_| AudioError::Codec)?;
+        let mut samples = Vec::new();
+        let mut n_errors = 0;
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break
+                }
+                Err(_) => return Err(AudioError::Decode),
+            };
+            if packet.track_id() != track_id {
+                continue;
+            }
+            match decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let spec = *decoded.spec();
+                    sample_rate = spec.rate;
+                    channels = spec.channels.count();
+                    let mut sb = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+                    sb.copy_interleaved_ref(decoded);
+                    samples.extend_from_slice(sb.samples());
+                }
+                // 壊れたパケットは読み飛ばす
+                Err(SymphoniaError::DecodeError(_)) => n_errors += 1,
+                Err(_) => return Err(AudioError::Decode),
+            }
+        }
+        if samples.is_empty() && n_errors > 0 {
+            return Err(AudioError::Decode);
+        }
+        resample(
+            downmix(samples, channels, self.channel)?,
+            sample_rate,
+            target_sample_rate,
+        )
+    }
+}
+
+fn downmix(
+    samples: Vec<f32>,
+    channels: usize,
+    channel: Option<usize>,
+) -> Result<Vec<f32>, AudioError> {
+    match channel {
+        Some(channel) if channel >= channels.max(1) => Err(AudioError::Channel(channel)),
+        _ if channels <= 1 => Ok(samples),
+        Some(channel) => Ok(samples
+            .chunks_exact(channels)
+            .map(|frame| frame[channel])
+            .collect()),
+        None => Ok(samples
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect()),
+    }
+}
+
+fn resample(samples: Vec<f32>, from: u32, to: u32) -> Result<Vec<f32>, AudioError> {
+    // 低いサンプリングレートを引き伸ばしても認識できる音声にはならない
+    if from < to {
+        return Err(AudioError::SampleRate(from));
+    }
+    if from == to || samples.is_empty() {
+        return Ok(samples);
+    }
+    let waves_out = SincFixedIn::<f32>::new(
+        to as f64 / from as f64,
+        2.0,
+        SincInterpolationParameters {
+            sinc_len: 256,
+            f_cutoff: 0.95,
+            interpolation: SincInterpolationType::Linear,
+            oversampling_factor: 256,
+            window: WindowFunction::BlackmanHarris2,
+        },
+        samples.len(),
+        1,
+    )
+    .map_err(|_| AudioError::Resample)?
+    .process(&[samples], None)
+    .map_err(|_| AudioError::Resample)?;
+    Ok(waves_out.into_iter().next().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pothook-test-{}-{}", std::process::id(), name))
+    }
+
+    // 左のチャンネルにだけ一定の値を入れた16ビットのステレオWAVを書き出す
+    fn write_left_only_wav(path: &Path, sample_rate: u32, frames: usize, left: i16) {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for _ in 0..frames {
+            writer.write_sample(left).unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    // [-1.0, 1.0]の値を指定の形式の16kHzモノラルWAVとして書き出す
+    fn write_mono_wav(path: &Path, sample_format: hound::SampleFormat, bits: u16, values: &[f32]) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: WHISPER_SAMPLE_RATE,
+            bits_per_sample: bits,
+            sample_format,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        let scale = ((1i64 << (bits - 1)) - 1) as f32;
+        for &value in values {
+            match (sample_format, bits) {
+                (hound::SampleFormat::Float, _) => writer.write_sample(value),
+                (_, 8) => writer.write_sample((value * scale).round() as i8),
+                (_, 16) => writer.write_sample((value * scale).round() as i16),
+                _ => writer.write_sample((value * scale).round() as i32),
+            }
+            .unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    const RAMP: [f32; 8] = [-1., -0.5, -0.25, 0., 0.25, 0.5, 0.75, 0.99];
+
+    fn assert_close(decoded: &[f32], expected: &[f32], tolerance: f32) {
+        assert_eq!(decoded.len(), expected.len());
+        for (d, e) in decoded.iter().zip(expected) {
+            assert!((d - e).abs() <= tolerance, "{} != {}", d, e);
+        }
+    }
+
+    #[test]
+    fn decode_wav_sample_formats() {
+        let formats = [
+            (hound::SampleFormat::Float, 32),
+            (hound::SampleFormat::Int, 8),
+            (hound::SampleFormat::Int, 16),
+            (hound::SampleFormat::Int, 24),
+            (hound::SampleFormat::Int, 32),
+        ];
+        for (sample_format, bits) in formats {
+            let temp = TempFile::new(temp_path(&format!("ramp-{:?}{}.wav", sample_format, bits)));
+            write_mono_wav(temp.path(), sample_format, bits, &RAMP);
+            let decoded = decode_to_f32_mono_16k(temp.path(), None, 0).unwrap();
+            // 整数は量子化の分だけずれる
+            let tolerance = match sample_format {
+                hound::SampleFormat::Float => 0.,
+                hound::SampleFormat::Int => 2. / (1i64 << (bits - 1)) as f32,
+            };
+            assert_close(&decoded, &RAMP, tolerance);
+        }
+    }
+
+    #[test]
+    fn decode_wav_below_16k_fails() {
+        let temp = TempFile::new(temp_path("8k.wav"));
+        write_left_only_wav(temp.path(), 8000, 80, 0);
+        assert!(matches!(
+            decode_to_f32_mono_16k(temp.path(), None, 0),
+            Err(AudioError::SampleRate(8000))
+        ));
+    }
+
+    // symphoniaもWAVを読めるので、同じファイルをsymphonia側の経路で読んで比べる
+    #[test]
+    fn decode_wav_with_symphonia() {
+        let temp = TempFile::new(temp_path("symphonia.wav"));
+        write_mono_wav(temp.path(), hound::SampleFormat::Int, 16, &RAMP);
+        let decoded = SymphoniaDecoder {
+            channel: None,
+            track: 0,
+        }
+        .decode_to_f32_mono(temp.path(), WHISPER_SAMPLE_RATE)
+        .unwrap();
+        assert_close(&decoded, &RAMP, 2. / 32768.);
+        assert!(matches!(
+            SymphoniaDecoder {
+                channel: None,
+                track: 1,
+            }
+            .decode_to_f32_mono(temp.path(), WHISPER_SAMPLE_RATE),
+            Err(AudioError::Track(1))
+        ));
+    }
+
+    #[test]
+    fn downmix_averages_or_picks_channel() {
+        let samples = vec![0.5, 0., 0.5, 0., -0.5, 0.];
+        assert_eq!(
+            downmix(samples.clone(), 2, None).unwrap(),
+            vec![0.25, 0.25, -0.25]
+        );
+        assert_eq!(
+            downmix(samples.clone(), 2, Some(0)).unwrap(),
+            vec![0.5, 0.5, -0.5]
+        );
+        assert_eq!(downmix(samples.clone(), 2, Some(1)).unwrap(), vec![0.; 3]);
+        assert!(matches!(
+            downmix(samples, 2, Some(2)),
+            Err(AudioError::Channel(2))
+        ));
+    }
+
+    #[test]
+    fn to_mono_16k_averages_stereo() {
+        let samples: Vec<f32> = (0..1600).flat_map(|_| [0.5, 0.]).collect();
+        let mono = to_mono_16k(samples, 2, WHISPER_SAMPLE_RATE).unwrap();
+        assert_eq!(mono.len(), 1600);
+        assert!(mono.iter().all(|&s| s == 0.25));
+    }
+
+    #[test]
+    fn decode_left_only_stereo_wav() {
+        let temp = TempFile::new(temp_path("left-only.wav"));
+        write_left_only_wav(temp.path(), WHISPER_SAMPLE_RATE, 1600, 16384);
+        let mono = decode_to_f32_mono_16k(temp.path(), None, 0).unwrap();
+        // 16384/32768 = 0.5 と無音の平均
+        assert_eq!(mono.len(), 1600);
+        assert!(mono.iter().all(|&s| (s - 0.25).abs() < 1e-6));
+        let right = decode_to_f32_mono_16k(temp.path(), Some(1), 0).unwrap();
+        assert_eq!(right.len(), 1600);
+        assert!(right.iter().all(|&s| s == 0.));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::error::WhisperError;
+use crate::export;
+use crate::store::STORE;
+use crate::whisper;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use tauri::Manager;
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct BatchProgress {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    current: usize,
+    total: usize,
+    file: String,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct BatchFailure {
+    file: String,
+    message: String,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct BatchSummary {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    total: usize,
+    succeeded: usize,
+    failed: Vec<BatchFailure>,
+    cancelled: bool,
+}
+
+pub async fn run_batch(paths: Vec<String>, app: tauri::AppHandle) -> Result<(), String> {
+    let total = paths.len();
+    // 前の実行で押された取り消しが残っていると、最初のファイルの前で止まってしまう
+    whisper::CANCEL_FLAG.store(false, Ordering::SeqCst);
+    // ファイルごとに結果を消すので、開いていた文字起こし結果は終わってから戻す
+    let (path_wav_before, transcript_before) = {
+        let mut store = STORE.lock().map_err(|_| "Mutex is poisoned")?;
+        (store.get_path_wav().to_path_buf(), store.take_transcript())
+    };
+    let mut succeeded = 0;
+    let mut failed = Vec::new();
+    let mut cancelled = false;
+
+    for (i, path) in paths.into_iter().enumerate() {
+        // whisper::runは開始時にフラグを戻すので、ファイルの合間に押された取り消しはここで拾う
+        if whisper::CANCEL_FLAG.load(Ordering::SeqCst) {
+            cancelled = true;
+            break;
+        }
+        let _ = app.emit_all(
+            "whisper",
+            BatchProgress {
+                status: "batch_progress".to_string(),
+                code: "batch_progress".to_string(),
+                params: serde_json::json!({ "current": i + 1, "total": total, "file": path }),
+                current: i + 1,
+                total,
+                file: path.clone(),
+            },
+        );
+        match transcribe_one(&path, &app).await {
+            Ok(()) => succeeded += 1,
+            Err(WhisperError::Cancelled) => {
+                cancelled = true;
+                break;
+            }
+            Err(err) => failed.push(BatchFailure {
+                file: path,
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    {
+        let mut store = STORE.lock().map_err(|_| "Mutex is poisoned")?;
+        store.set_path_wav(&app, path_wav_before);
+        store.restore_transcript(&app, transcript_before);
+    }
+    let _ = app.emit_all(
+        "whisper",
+        BatchSummary {
+            status: "batch_done".to_string(),
+            code: "batch_done".to_string(),
+            params: serde_json::json!({
+                "total": total,
+                "succeeded": succeeded,
+                "failed": failed.len(),
+                "cancelled": cancelled,
+            }),
+            total,
+            succeeded,
+            failed,
+            cancelled,
+        },
+    );
+    Ok(())
+}
+
+pub async fn transcribe_one(path: &str, app: &tauri::AppHandle) -> Result<(), WhisperError> {
+    {
+        let mut store = STORE.lock().map_err(|_| WhisperError::Poisoned)?;
+        store.set_path_wav(app, PathBuf::from(path));
+        store.clear_data(app);
+    }
+    let mut result =
+        whisper::run(path, "", "", false, 0, 0, whisper::CANCEL_FLAG.clone(), app).await;
+    // 一時的な失敗なら1回だけやり直す
+    if let Err(err) = &result {
+        if err.is_retriable() && !whisper::CANCEL_FLAG.load(Ordering::SeqCst) {
+            tracing::warn!("retrying {}: {}", path, err);
+            STORE
+                .lock()
+                .map_err(|_| WhisperError::Poisoned)?
+                .clear_data(app);
+            result =
+                whisper::run(path, "", "", false, 0, 0, whisper::CANCEL_FLAG.clone(), app).await;
+        }
+    }
+    result?;
+    export::export_srt(&PathBuf::from(path).with_extension("srt")).map_err(|err| {
+        WhisperError::Export {
+            message: err.to_string(),
+        }
+    })
+}
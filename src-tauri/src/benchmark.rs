@@ -0,0 +1,239 @@
+use crate::audio;
+use crate::model_cache::{self, MODEL_CACHE};
+use crate::models;
+use crate::store::STORE;
+use crate::whisper::{self, CANCEL_FLAG};
+use libc::c_void;
+use std::f32::consts::PI;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tauri::Manager;
+use whisper_rs::FullParams;
+
+const MAX_BENCHMARK_SEC: f64 = 300.;
+// whisperのエンコーダーは30秒ずつ処理する
+const WINDOW_MS: u64 = 30_000;
+
+#[derive(Clone, serde::Serialize, Debug)]
+pub struct BenchmarkResult {
+    model_path: PathBuf,
+    model_bytes: u64,
+    duration_ms: u64,
+    n_threads: i32,
+    backend: String,
+    load_ms: u64,
+    encode_ms: u64,
+    decode_ms: u64,
+    total_ms: u64,
+    // 処理時間を音声の長さで割った値。1より小さければ実時間より速い
+    realtime_factor: f32,
+    timestamp: u64,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct BenchmarkProgressPayload {
+    stage: String,
+    progress: i32,
+}
+
+struct BenchmarkCallbackData {
+    app: tauri::AppHandle,
+    cancel: Arc<AtomicBool>,
+}
+
+pub fn history_path(app: &tauri::AppHandle) -> Option<PathBuf> {
+    tauri::api::path::app_data_dir(&app.config()).map(|dir| dir.join("pothook/benchmarks.jsonl"))
+}
+
+// 今の設定のまま、合成した音声をmodel_pathのモデルで文字起こしして時間を測る。
+// 結果はStoreのセグメントには入れない
+pub async fn run(
+    model_path: PathBuf,
+    duration_s: f64,
+    save: bool,
+    app: tauri::AppHandle,
+) -> Result<BenchmarkResult, String> {
+    if !(1. ..=MAX_BENCHMARK_SEC).contains(&duration_s) {
+        return Err(format!(
+            "ベンチマークの長さは1〜{}秒で指定してください: {}",
+            MAX_BENCHMARK_SEC, duration_s
+        ));
+    }
+    let result = {
+        let app = app.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            run_blocking(model_path, (duration_s * 1000.) as u64, &app)
+        })
+        .await
+        .map_err(|_| "ベンチマークのスレッドが異常終了しました".to_string())??
+    };
+    if save {
+        if let Err(err) = append_history(&app, &result) {
+            tracing::warn!("failed to save benchmark: {}", err);
+        }
+    }
+    Ok(result)
+}
+
+fn run_blocking(
+    model_path: PathBuf,
+    duration_ms: u64,
+    app: &tauri::AppHandle,
+) -> Result<BenchmarkResult, String> {
+    // 文字起こしと同時に走らせるとメモリも時間も取り合うので、終わるまで待つ
+    let _running = MODEL_CACHE.lock_run()?;
+    // 待っている間の取り消しは実行中の文字起こしに向けたものなので、ロックを取ってから消す
+    let cancel = CANCEL_FLAG.clone();
+    cancel.store(false, Ordering::SeqCst);
+    if cancel.load(Ordering::SeqCst) {
+        return Err("cancelled".to_string());
+    }
+    let (sampling_strategy, lang, translate, n_threads, gpu) = {
+        let config = STORE.lock().map_err(|_| "Mutex is poisoned")?;
+        (
+            config.get_sampling_strategy(),
+            // 合成音は言語を判定できないので、自動判定のときは英語として扱う
+            (!config.is_auto_lang()).then(|| config.get_lang().unwrap_or("en").to_string()),
+            config.get_translate(),
+            config.get_n_threads(),
+            config.get_gpu_settings(),
+        )
+    };
+    models::validate(&model_path, false).map_err(|err| err.to_string())?;
+    let model_bytes = fs::metadata(&model_path).map_or(0, |meta| meta.len());
+
+    emit_progress(app, "loading", 0);
+    let started = Instant::now();
+    let loaded = model_cache::load(&model_path, gpu)?;
+    let context = loaded.context;
+    let load_ms = started.elapsed().as_millis() as u64;
+    if cancel.load(Ordering::SeqCst) {
+        return Err("cancelled".to_string());
+    }
+
+    let samples = sample_audio(duration_ms);
+    let mut state = context
+        .create_state()
+        .map_err(|_| "Whisper Stateの初期化に失敗しました".to_string())?;
+    // 最初の30秒だけエンコードして、1区間あたりの時間を測る
+    emit_progress(app, "encoding", 0);
+    let window_len =
+        (WINDOW_MS as usize * audio::WHISPER_SAMPLE_RATE as usize / 1000).min(samples.len());
+    let started = Instant::now();
+    state
+        .pcm_to_mel(&samples[..window_len], n_threads.max(1) as usize)
+        .and_then(|_| state.encode(0, n_threads.max(1) as usize))
+        .map_err(|_| "エンコードに失敗しました".to_string())?;
+    let window_encode_ms = started.elapsed().as_millis() as u64;
+    if cancel.load(Ordering::SeqCst) {
+        return Err("cancelled".to_string());
+    }
+
+    emit_progress(app, "decoding", 0);
+    let callback_data = BenchmarkCallbackData {
+        app: app.clone(),
+        cancel: cancel.clone(),
+    };
+    let user_data = &callback_data as *const BenchmarkCallbackData as *mut c_void;
+    let mut params = FullParams::new(whisper::to_sampling_strategy(sampling_strategy));
+    params.set_language(Some(lang.as_deref().unwrap_or("en")));
+    params.set_translate(translate);
+    params.set_n_threads(n_threads);
+    params.set_print_progress(false);
+    params.set_print_realtime(false);
+    unsafe {
+        params.set_start_encoder_callback(Some(encoder_begin_callback));
+        params.set_start_encoder_callback_user_data(user_data);
+        params.set_progress_callback(Some(progress_callback));
+        params.set_progress_callback_user_data(user_data);
+    }
+    let started = Instant::now();
+    let result = state.full(params, &samples);
+    let total_ms = started.elapsed().as_millis() as u64;
+    if cancel.load(Ordering::SeqCst) {
+        return Err("cancelled".to_string());
+    }
+    result.map_err(|_| "言語モデルの実行に失敗しました".to_string())?;
+
+    // state.fullは区間ごとにエンコードしてからデコードするので、エンコードの分を引いてデコードの時間とする
+    let encode_ms = window_encode_ms * duration_ms.div_ceil(WINDOW_MS);
+    emit_progress(app, "done", 100);
+    Ok(BenchmarkResult {
+        model_path,
+        model_bytes,
+        duration_ms,
+        n_threads,
+        backend: loaded.backend,
+        load_ms,
+        encode_ms,
+        decode_ms: total_ms.saturating_sub(encode_ms),
+        total_ms,
+        realtime_factor: total_ms as f32 / duration_ms as f32,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+    })
+}
+
+// 声に近い倍音と1秒に4回ほどの抑揚を持つ合成音。毎回同じ音なので結果を比べられる
+fn sample_audio(duration_ms: u64) -> Vec<f32> {
+    let rate = audio::WHISPER_SAMPLE_RATE as f32;
+    let len = duration_ms as usize * audio::WHISPER_SAMPLE_RATE as usize / 1000;
+    (0..len)
+        .map(|i| {
+            let t = i as f32 / rate;
+            let f0 = 140. + 30. * (2. * PI * 0.5 * t).sin();
+            let envelope = (0.5 + 0.5 * (2. * PI * 4. * t).sin()).powi(2);
+            let voice: f32 = (1..=8)
+                .map(|k| (2. * PI * f0 * k as f32 * t).sin() / k as f32)
+                .sum();
+            0.1 * envelope * voice
+        })
+        .collect()
+}
+
+unsafe extern "C" fn encoder_begin_callback(
+    _: *mut whisper_rs_sys::whisper_context,
+    _: *mut whisper_rs_sys::whisper_state,
+    user_data: *mut c_void,
+) -> bool {
+    !(*(user_data as *const BenchmarkCallbackData))
+        .cancel
+        .load(Ordering::SeqCst)
+}
+
+unsafe extern "C" fn progress_callback(
+    _: *mut whisper_rs_sys::whisper_context,
+    _: *mut whisper_rs_sys::whisper_state,
+    progress: i32,
+    user_data: *mut c_void,
+) {
+    let data = &*(user_data as *const BenchmarkCallbackData);
+    emit_progress(&data.app, "decoding", progress);
+}
+
+fn emit_progress(app: &tauri::AppHandle, stage: &str, progress: i32) {
+    let _ = app.emit_all(
+        "benchmark",
+        BenchmarkProgressPayload {
+            stage: stage.to_string(),
+            progress,
+        },
+    );
+}
+
+// 1回分を1行のJSONとして追記する
+fn append_history(app: &tauri::AppHandle, result: &BenchmarkResult) -> std::io::Result<()> {
+    let Some(path) = history_path(app) else {
+        return Ok(());
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(result)?)
+}
@@ -0,0 +1,131 @@
+use crate::audio::{self, WHISPER_SAMPLE_RATE};
+use std::fmt;
+use std::path::PathBuf;
+
+// フロントエンドで種類ごとに処理を分けられるよう、codeに種類の名前を入れて送る
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum WhisperError {
+    Poisoned,
+    Thread,
+    Config {
+        message: String,
+    },
+    WavOpen {
+        message: String,
+    },
+    WavDecode {
+        message: String,
+    },
+    // 読めるが形式が対応していないもの。expectedとgotは言語によらない表記にする
+    AudioInvalidFormat {
+        message: String,
+        expected: String,
+        got: String,
+    },
+    ModelLoad {
+        path: PathBuf,
+        message: String,
+    },
+    StateInit,
+    LanguageDetect {
+        message: String,
+    },
+    LanguageUncertain,
+    Inference,
+    SegmentText,
+    EventEmit,
+    // 文字起こしは終わったが、一括処理やキューで字幕を書き出せなかった
+    Export {
+        message: String,
+    },
+    Cancelled,
+}
+
+impl WhisperError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            WhisperError::Poisoned => "poisoned",
+            WhisperError::Thread => "thread",
+            WhisperError::Config { .. } => "config",
+            WhisperError::WavOpen { .. } => "wav_open",
+            WhisperError::WavDecode { .. } => "wav_decode",
+            WhisperError::AudioInvalidFormat { .. } => "audio_invalid_format",
+            WhisperError::ModelLoad { .. } => "model_load",
+            WhisperError::StateInit => "state_init",
+            WhisperError::LanguageDetect { .. } => "language_detect",
+            WhisperError::LanguageUncertain => "language_uncertain",
+            WhisperError::Inference => "inference",
+            WhisperError::SegmentText => "segment_text",
+            WhisperError::EventEmit => "event_emit",
+            WhisperError::Export { .. } => "export",
+            WhisperError::Cancelled => "cancelled",
+        }
+    }
+
+    // 同じファイルでもう一度実行すれば通る見込みがあるか。
+    // ファイルや設定が原因のものは何度やっても同じなので含めない
+    pub fn is_retriable(&self) -> bool {
+        matches!(
+            self,
+            WhisperError::Thread
+                | WhisperError::StateInit
+                | WhisperError::Inference
+                | WhisperError::EventEmit
+        )
+    }
+}
+
+impl fmt::Display for WhisperError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WhisperError::Poisoned => write!(f, "Mutex is poisoned"),
+            WhisperError::Thread => write!(f, "文字起こしのスレッドが異常終了しました"),
+            WhisperError::Config { message }
+            | WhisperError::WavOpen { message }
+            | WhisperError::WavDecode { message }
+            | WhisperError::AudioInvalidFormat { message, .. }
+            | WhisperError::ModelLoad { message, .. }
+            | WhisperError::LanguageDetect { message }
+            | WhisperError::Export { message } => write!(f, "{}", message),
+            WhisperError::StateInit => write!(f, "Whisper Stateの初期化に失敗しました"),
+            WhisperError::LanguageUncertain => {
+                write!(f, "言語を判定できませんでした。言語を指定してください")
+            }
+            WhisperError::Inference => write!(f, "言語モデルの実行に失敗しました"),
+            WhisperError::SegmentText => {
+                write!(f, "Text segment could not be converted to string.")
+            }
+            WhisperError::EventEmit => write!(f, "イベントの送信に失敗しました"),
+            WhisperError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for WhisperError {}
+
+impl From<audio::AudioError> for WhisperError {
+    fn from(err: audio::AudioError) -> Self {
+        let message = err.to_string();
+        match err {
+            audio::AudioError::Open
+            | audio::AudioError::Unsupported
+            | audio::AudioError::NoTrack
+            | audio::AudioError::Track(_) => WhisperError::WavOpen { message },
+            audio::AudioError::SampleRate(rate) => WhisperError::AudioInvalidFormat {
+                message,
+                expected: format!(">= {} Hz", WHISPER_SAMPLE_RATE),
+                got: format!("{} Hz", rate),
+            },
+            audio::AudioError::WavFormat(format, bits) => WhisperError::AudioInvalidFormat {
+                message,
+                expected: "int8/16/24/32, float32".to_string(),
+                got: match format {
+                    hound::SampleFormat::Float => format!("float{}", bits),
+                    hound::SampleFormat::Int => format!("int{}", bits),
+                },
+            },
+            _ => WhisperError::WavDecode { message },
+        }
+    }
+}
@@ -0,0 +1,764 @@
+use crate::store::{Data, SpeakerTurnMark, Word, STORE};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// 長さ0のセグメントに与える表示時間
+const MIN_CUE_MS: i64 = 500;
+
+#[derive(Debug, Clone)]
+pub struct Cue {
+    pub index: usize,
+    pub ms_start: i64,
+    pub ms_end: i64,
+    pub text: String,
+    pub words: Vec<Word>,
+    pub speaker: String,
+    pub speaker_turn: bool,
+    pub confidence: f32,
+    pub edited: bool,
+}
+
+// import_jsonで読み込み直せるJSON。segmentsと主な設定を持つ
+#[derive(serde::Serialize, serde::Deserialize)]
+struct JsonExport {
+    // versionだけを見る読み手もschema_versionだけを見る読み手もいるので、同じ値を両方に書く
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    schema_version: u32,
+    #[serde(default)]
+    config: JsonConfig,
+    #[serde(default)]
+    segments: Vec<JsonSegment>,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct JsonConfig {
+    #[serde(default)]
+    model: String,
+    #[serde(default)]
+    lang: String,
+    #[serde(default)]
+    translate: bool,
+    #[serde(default)]
+    initial_prompt: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct JsonSegment {
+    index: usize,
+    t0: i64,
+    t1: i64,
+    text: String,
+    #[serde(default)]
+    confidence: f32,
+    // 読み込むときはspeaker_turnから振り直すので使わない
+    #[serde(default)]
+    speaker: String,
+    #[serde(default)]
+    speaker_turn: bool,
+    #[serde(default)]
+    edited: bool,
+}
+
+const JSON_VERSION: u32 = 1;
+
+// 書き出す範囲（ミリ秒）。範囲に収まるセグメントだけを書き出す
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TimeRange {
+    pub start_ms: Option<i64>,
+    pub end_ms: Option<i64>,
+}
+
+impl TimeRange {
+    fn contains(&self, cue: &Cue) -> bool {
+        self.start_ms.is_none_or(|start| cue.ms_start >= start)
+            && self.end_ms.is_none_or(|end| cue.ms_end <= end)
+    }
+}
+
+// テキスト書き出しでセグメントの間に入れるもの
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextSeparator {
+    Newline,
+    Space,
+    // 1秒より長い間があいたところだけ空行を入れる
+    ParagraphBreak,
+}
+
+const PARAGRAPH_GAP_MS: i64 = 1000;
+
+impl FromStr for TextSeparator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newline" | "" => Ok(TextSeparator::Newline),
+            "space" => Ok(TextSeparator::Space),
+            "paragraph" => Ok(TextSeparator::ParagraphBreak),
+            _ => Err(format!("区切りの指定が不正です: {}", s)),
+        }
+    }
+}
+
+// 2人目以降の話者に順に割り当てる文字色
+const SPEAKER_COLOURS: [&str; 6] = [
+    "#FFFF00", "#00FFFF", "#FF80FF", "#80FF80", "#FF8040", "#8080FF",
+];
+
+// 既定のスタイルは720pで読みやすい大きさにしてある
+const ASS_PLAY_RES_X: u32 = 1280;
+const ASS_PLAY_RES_Y: u32 = 720;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AssStyle {
+    pub font_name: String,
+    pub font_size: u32,
+    pub primary_colour: String,
+    pub outline_colour: String,
+    pub back_colour: String,
+    pub bold: bool,
+    pub outline: f32,
+    pub shadow: f32,
+    pub margin_v: u32,
+}
+
+impl Default for AssStyle {
+    fn default() -> Self {
+        AssStyle {
+            font_name: "Arial".to_string(),
+            font_size: 48,
+            primary_colour: "#FFFFFF".to_string(),
+            outline_colour: "#000000".to_string(),
+            back_colour: "#000000".to_string(),
+            bold: false,
+            outline: 2.,
+            shadow: 0.,
+            margin_v: 20,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(std::io::Error),
+    Lock,
+    Read(std::io::Error),
+    Parse(String),
+    Serialize(serde_json::Error),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Io(err) => write!(f, "字幕ファイルの書き出しに失敗しました: {}", err),
+            ExportError::Lock => write!(f, "文字起こし結果を読み出せませんでした"),
+            ExportError::Read(err) => write!(f, "ファイルを読み込めませんでした: {}", err),
+            ExportError::Parse(err) => write!(f, "ファイルの内容が正しくありません: {}", err),
+            ExportError::Serialize(err) => write!(f, "JSONに変換できませんでした: {}", err),
+            ExportError::UnsupportedVersion(version) => write!(
+                f,
+                "このファイルの形式（version {}）には対応していません",
+                version
+            ),
+        }
+    }
+}
+
+impl From<std::io::Error> for ExportError {
+    fn from(err: std::io::Error) -> Self {
+        ExportError::Io(err)
+    }
+}
+
+pub fn export_srt(path: &Path) -> Result<(), ExportError> {
+    let (cues, line_length, turn_mark) = {
+        let store = STORE.lock().map_err(|_| ExportError::Lock)?;
+        (
+            store.cues(),
+            store.get_line_length(),
+            store.get_speaker_turn_mark(),
+        )
+    };
+    let cues = normalize(cues);
+    let srt = cues
+        .iter()
+        .enumerate()
+        .map(|(i, cue)| {
+            format!(
+                "{}\n{} --> {}\n{}{}\n\n",
+                i + 1,
+                ts(cue.ms_start).replace('.', ","),
+                ts(cue.ms_end).replace('.', ","),
+                cue_turn_prefix(&cues, i, turn_mark),
+                wrap(&cue.text, line_length)
+            )
+        })
+        .collect::<String>();
+    write_atomic(path, &srt)
+}
+
+// word_cuesがtrueで単語のタイムスタンプがあれば、<c>タグで単語ごとの時刻を埋め込む
+pub fn export_vtt(path: &Path, word_cues: bool) -> Result<(), ExportError> {
+    let file_name = |path: &Path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().replace("-->", "->"))
+            .unwrap_or_default()
+    };
+    let (cues, line_length, turn_mark, mut vtt) = {
+        let store = STORE.lock().map_err(|_| ExportError::Lock)?;
+        let header = format!(
+            "WEBVTT\n\nNOTE\nsource: {}\nmodel: {}\nlanguage: {}\n\n",
+            file_name(store.get_path_wav()),
+            file_name(store.get_path_model()),
+            store.get_effective_lang()
+        );
+        (
+            store.cues(),
+            store.get_line_length(),
+            store.get_speaker_turn_mark(),
+            header,
+        )
+    };
+    vtt.push_str(&vtt_cues(
+        &normalize(cues),
+        line_length,
+        turn_mark,
+        word_cues,
+    ));
+    write_atomic(path, &vtt)
+}
+
+fn vtt_cues(
+    cues: &[Cue],
+    line_length: usize,
+    turn_mark: SpeakerTurnMark,
+    word_cues: bool,
+) -> String {
+    let mut vtt = String::new();
+    for (i, cue) in cues.iter().enumerate() {
+        let text = if word_cues && !cue.words.is_empty() {
+            vtt_word_text(cue)
+        } else {
+            escape_vtt(&wrap(&cue.text, line_length))
+        };
+        vtt.push_str(&format!(
+            "{}\n{} --> {}\n{}{}\n\n",
+            i + 1,
+            ts(cue.ms_start),
+            ts(cue.ms_end),
+            escape_vtt(cue_turn_prefix(cues, i, turn_mark)),
+            text
+        ));
+    }
+    vtt
+}
+
+// 範囲を指定しなければ全セグメントを書き出し、import_jsonでそのまま読み込み直せる
+pub fn export_json(path: &Path, range: TimeRange) -> Result<(), ExportError> {
+    let (cues, config) = {
+        let store = STORE.lock().map_err(|_| ExportError::Lock)?;
+        let config = JsonConfig {
+            model: store
+                .get_path_model()
+                .file_name()
+                .map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+            lang: store.get_lang().unwrap_or_default().to_string(),
+            translate: store.get_translate(),
+            initial_prompt: store.get_initial_prompt().map(str::to_string),
+        };
+        (store.cues(), config)
+    };
+    let export = JsonExport {
+        version: JSON_VERSION,
+        schema_version: JSON_VERSION,
+        config,
+        segments: cues
+            .into_iter()
+            .filter(|cue| range.contains(cue))
+            .map(|cue| JsonSegment {
+                index: cue.index,
+                t0: cue.ms_start,
+                t1: cue.ms_end,
+                text: cue.text.trim().to_string(),
+                confidence: cue.confidence,
+                speaker: cue.speaker,
+                speaker_turn: cue.speaker_turn,
+                edited: cue.edited,
+            })
+            .collect(),
+    };
+    let json = serde_json::to_string_pretty(&export).map_err(ExportError::Serialize)?;
+    write_atomic(path, &json)
+}
+
+// export_jsonで書き出したファイルを読み込み、今のセグメントと入れ替える。
+// 入れ替えは1回の操作として記録するので取り消せる。読み込んだセグメントの数を返す
+pub fn import_json(app: &tauri::AppHandle, path: &Path) -> Result<usize, ExportError> {
+    let json = fs::read_to_string(path).map_err(ExportError::Read)?;
+    let export: JsonExport =
+        serde_json::from_str(&json).map_err(|err| ExportError::Parse(err.to_string()))?;
+    // 片方しか書いていないファイルも読めるよう、書いてある方を使う
+    let version = export.schema_version.max(export.version);
+    if !(1..=JSON_VERSION).contains(&version) {
+        return Err(ExportError::UnsupportedVersion(version));
+    }
+    let mut segments = export.segments;
+    segments.sort_by_key(|segment| segment.index);
+    let mut data = Vec::with_capacity(segments.len());
+    for segment in segments {
+        if segment.t1 < segment.t0 {
+            return Err(ExportError::Parse(format!(
+                "セグメント{}の終了時刻が開始時刻より前です",
+                segment.index
+            )));
+        }
+        data.push(Data::new(
+            segment.t0,
+            segment.t1,
+            segment.text,
+            Vec::new(),
+            segment.confidence,
+            segment.speaker_turn,
+            false,
+        ));
+    }
+    let count = data.len();
+    let mut store = STORE.lock().map_err(|_| ExportError::Lock)?;
+    store.replace_segments(app, data);
+    if !export.config.lang.is_empty() {
+        store.set_lang(app, export.config.lang);
+    }
+    store.set_translate(app, export.config.translate);
+    store.set_initial_prompt(app, export.config.initial_prompt);
+    Ok(count)
+}
+
+pub fn export_csv(path: &Path, range: TimeRange) -> Result<(), ExportError> {
+    let cues = STORE.lock().map_err(|_| ExportError::Lock)?.cues();
+    write_atomic(path, &csv_cues(&cues, range))
+}
+
+fn csv_cues(cues: &[Cue], range: TimeRange) -> String {
+    let mut csv = "index,t0_ms,t1_ms,t0_srt,t1_srt,text,confidence,speaker\n".to_string();
+    for cue in cues.iter().filter(|cue| range.contains(cue)) {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{}\n",
+            cue.index,
+            cue.ms_start,
+            cue.ms_end,
+            escape_csv(&ts(cue.ms_start).replace('.', ",")),
+            escape_csv(&ts(cue.ms_end).replace('.', ",")),
+            escape_csv(cue.text.trim()),
+            cue.confidence,
+            escape_csv(&cue.speaker)
+        ));
+    }
+    csv
+}
+
+pub fn export_txt(path: &Path, separator: TextSeparator) -> Result<(), ExportError> {
+    let (cues, turn_mark) = {
+        let store = STORE.lock().map_err(|_| ExportError::Lock)?;
+        (normalize(store.cues()), store.get_speaker_turn_mark())
+    };
+    let mut txt = String::new();
+    for (i, cue) in cues.iter().enumerate() {
+        if i > 0 {
+            let turn = is_speaker_turn(&cues, i);
+            txt.push_str(match (separator, turn_mark) {
+                (_, SpeakerTurnMark::Blank) if turn => "\n\n",
+                (TextSeparator::Space, SpeakerTurnMark::Chevron) if turn => " >> ",
+                (_, SpeakerTurnMark::Chevron) if turn => "\n>> ",
+                (TextSeparator::Space, _) => " ",
+                (TextSeparator::ParagraphBreak, _)
+                    if cue.ms_start - cues[i - 1].ms_end > PARAGRAPH_GAP_MS =>
+                {
+                    "\n\n"
+                }
+                _ => "\n",
+            });
+        }
+        txt.push_str(&cue.text);
+    }
+    txt.push('\n');
+    write_atomic(path, &txt)
+}
+
+pub fn export_ass(path: &Path, style: &AssStyle) -> Result<(), ExportError> {
+    let (cues, title) = {
+        let store = STORE.lock().map_err(|_| ExportError::Lock)?;
+        let title = store
+            .get_path_wav()
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        (store.cues(), title)
+    };
+    let cues = normalize(cues);
+    let mut speakers: Vec<&str> = Vec::new();
+    for cue in &cues {
+        if !speakers.contains(&cue.speaker.as_str()) {
+            speakers.push(&cue.speaker);
+        }
+    }
+    if speakers.is_empty() {
+        speakers.push("Speaker 1");
+    }
+
+    let mut ass = format!(
+        "[Script Info]\nTitle: {}\nScriptType: v4.00+\nWrapStyle: 0\nScaledBorderAndShadow: yes\nPlayResX: {}\nPlayResY: {}\n\n",
+        title, ASS_PLAY_RES_X, ASS_PLAY_RES_Y
+    );
+    ass.push_str("[V4+ Styles]\nFormat: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n");
+    for (i, speaker) in speakers.iter().enumerate() {
+        let primary_colour = match i {
+            0 => style.primary_colour.as_str(),
+            _ => SPEAKER_COLOURS[(i - 1) % SPEAKER_COLOURS.len()],
+        };
+        ass.push_str(&format!(
+            "Style: {},{},{},{},&H000000FF,{},{},{},0,0,0,100,100,0,0,1,{},{},2,10,10,{},1\n",
+            speaker.replace(',', " "),
+            style.font_name.replace(',', " "),
+            style.font_size,
+            ass_colour(primary_colour),
+            ass_colour(&style.outline_colour),
+            ass_colour(&style.back_colour),
+            if style.bold { -1 } else { 0 },
+            style.outline,
+            style.shadow,
+            style.margin_v
+        ));
+    }
+    ass.push_str(
+        "\n[Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+    );
+    for cue in &cues {
+        ass.push_str(&format!(
+            "Dialogue: 0,{},{},{},,0,0,0,,{}\n",
+            ass_ts(cue.ms_start),
+            ass_ts(cue.ms_end),
+            cue.speaker.replace(',', " "),
+            escape_ass(&cue.text)
+        ));
+    }
+    write_atomic(path, &ass)
+}
+
+// 表計算ソフトが数値や日付として読み替えないよう、文字列の列は常に引用符で囲む
+fn escape_csv(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
+// ASSの時刻はH:MM:SS.cc（センチ秒）
+fn ass_ts(ms: i64) -> String {
+    format!(
+        "{}:{:0>2}:{:0>2}.{:0>2}",
+        ms / 3600000,
+        (ms % 3600000) / 60000,
+        (ms % 60000) / 1000,
+        (ms % 1000) / 10
+    )
+}
+
+// "#RRGGBB"をASSの"&HAABBGGRR"に変換する。読めない値は白にする
+fn ass_colour(colour: &str) -> String {
+    // &HAABBGGRRの形で渡されたときはそのまま使う
+    if let Some(abgr) = colour
+        .strip_prefix("&H")
+        .or_else(|| colour.strip_prefix("&h"))
+    {
+        if abgr.len() == 8 && abgr.chars().all(|c| c.is_ascii_hexdigit()) {
+            return format!("&H{}", abgr.to_uppercase());
+        }
+    }
+    let hex = colour.trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return "&H00FFFFFF".to_string();
+    }
+    format!("&H00{}{}{}", &hex[4..6], &hex[2..4], &hex[0..2]).to_uppercase()
+}
+
+// Textは最後のフィールドなのでカンマはそのままでよいが、改行と{}の上書きタグは崩れるので置き換える
+fn escape_ass(text: &str) -> String {
+    text.replace('\n', "\\N")
+        .replace('{', "（")
+        .replace('}', "）")
+}
+
+// タイムスタンプタグはキューの開始と終了の間にないといけないので、範囲外の単語には付けない
+fn vtt_word_text(cue: &Cue) -> String {
+    let mut text = String::new();
+    for (i, word) in cue.words.iter().enumerate() {
+        let word_text = escape_vtt(if i == 0 {
+            word.word.trim_start()
+        } else {
+            &word.word
+        });
+        if i > 0 && word.t0 > cue.ms_start && word.t0 < cue.ms_end {
+            text.push_str(&format!("<{}>", ts(word.t0)));
+        }
+        text.push_str(&format!("<c>{}</c>", word_text));
+    }
+    text
+}
+
+// 本文に"-->"や"<"があるとキューの区切りやタグと解釈されるのでエスケープする
+fn escape_vtt(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// 前のキューと話者が違えば、ここで話者が替わっている
+fn is_speaker_turn(cues: &[Cue], i: usize) -> bool {
+    i > 0 && cues[i].speaker != cues[i - 1].speaker
+}
+
+fn cue_turn_prefix(cues: &[Cue], i: usize, turn_mark: SpeakerTurnMark) -> &'static str {
+    if turn_mark != SpeakerTurnMark::None && is_speaker_turn(cues, i) {
+        ">> "
+    } else {
+        ""
+    }
+}
+
+// 空のセグメントを落とし、長さ0のものや次のセグメントに食い込むものの終了時刻を直す
+fn normalize(cues: Vec<Cue>) -> Vec<Cue> {
+    let mut cues: Vec<Cue> = cues
+        .into_iter()
+        .map(|cue| Cue {
+            text: cue.text.trim().to_string(),
+            ..cue
+        })
+        .filter(|cue| !cue.text.is_empty())
+        .collect();
+    for i in 0..cues.len() {
+        let ms_start = cues[i].ms_start;
+        let mut ms_end = cues[i].ms_end;
+        if ms_end <= ms_start {
+            ms_end = ms_start + MIN_CUE_MS;
+        }
+        if let Some(next) = cues.get(i + 1) {
+            if ms_end > next.ms_start {
+                ms_end = next.ms_start.max(ms_start + 1);
+            }
+        }
+        cues[i].ms_end = ms_end;
+    }
+    cues
+}
+
+// 書き出し途中で落ちても壊れたファイルが残らないよう、一時ファイルに書いてから置き換える
+fn write_atomic(path: &Path, contents: &str) -> Result<(), ExportError> {
+    let mut temp = OsString::from(path.as_os_str());
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    if let Err(err) = fs::write(&temp, contents).and_then(|_| fs::rename(&temp, path)) {
+        let _ = fs::remove_file(&temp);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+pub fn ts(ms: i64) -> String {
+    format!(
+        "{:0>2}:{:0>2}:{:0>2}.{:0>3}",
+        ms / 3600000,
+        (ms % 3600000) / 60000,
+        (ms % 60000) / 1000,
+        ms % 1000
+    )
+}
+
+// 単語の途中では改行しない。limitより長い単語はそのまま1行にする
+fn wrap(text: &str, limit: usize) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.chars().count() + 1 + word.chars().count() <= limit => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(ms_start: i64, ms_end: i64, text: &str, words: Vec<Word>, speaker: &str) -> Cue {
+        Cue {
+            index: 0,
+            ms_start,
+            ms_end,
+            text: text.to_string(),
+            words,
+            speaker: speaker.to_string(),
+            speaker_turn: false,
+            confidence: 1.,
+            edited: false,
+        }
+    }
+
+    fn word(word: &str, t0: i64, t1: i64) -> Word {
+        Word {
+            word: word.to_string(),
+            t0,
+            t1,
+            probability: 1.,
+        }
+    }
+
+    fn parse_ts(ts: &str) -> i64 {
+        let (hms, ms) = ts.split_once('.').unwrap();
+        let hms: Vec<i64> = hms.split(':').map(|n| n.parse().unwrap()).collect();
+        ((hms[0] * 60 + hms[1]) * 60 + hms[2]) * 1000 + ms.parse::<i64>().unwrap()
+    }
+
+    // 書き出したキューを読んで、番号・開始・終了・タグを外した本文・単語の時刻にする
+    struct ParsedCue {
+        id: usize,
+        ms_start: i64,
+        ms_end: i64,
+        text: String,
+        word_times: Vec<i64>,
+    }
+
+    fn parse_vtt(vtt: &str) -> Vec<ParsedCue> {
+        vtt.split("\n\n")
+            .filter(|block| !block.is_empty())
+            .map(|block| {
+                let mut lines = block.lines();
+                let id = lines.next().unwrap().parse().unwrap();
+                let (start, end) = lines.next().unwrap().split_once(" --> ").unwrap();
+                let body = lines.collect::<Vec<_>>().join(" ");
+                let mut text = String::new();
+                let mut word_times = Vec::new();
+                let mut rest = body.as_str();
+                while let Some(open) = rest.find('<') {
+                    text.push_str(&rest[..open]);
+                    let close = open + rest[open..].find('>').unwrap();
+                    let tag = &rest[open + 1..close];
+                    if tag.starts_with(|c: char| c.is_ascii_digit()) {
+                        word_times.push(parse_ts(tag));
+                    }
+                    rest = &rest[close + 1..];
+                }
+                text.push_str(rest);
+                ParsedCue {
+                    id,
+                    ms_start: parse_ts(start),
+                    ms_end: parse_ts(end),
+                    text: text
+                        .replace("&lt;", "<")
+                        .replace("&gt;", ">")
+                        .replace("&amp;", "&"),
+                    word_times,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ts_formats_hours_and_milliseconds() {
+        assert_eq!(ts(0), "00:00:00.000");
+        assert_eq!(ts(3_723_456), "01:02:03.456");
+        assert_eq!(ts(36_000_000 + 59_999), "10:00:59.999");
+    }
+
+    #[test]
+    fn vtt_round_trip() {
+        let cues = normalize(vec![
+            cue(0, 1_500, "a --> b & <c>", Vec::new(), ""),
+            cue(
+                3_723_456,
+                3_725_000,
+                "long text to be wrapped",
+                Vec::new(),
+                "",
+            ),
+        ]);
+        let parsed = parse_vtt(&vtt_cues(&cues, 10, SpeakerTurnMark::None, false));
+        assert_eq!(parsed.len(), cues.len());
+        for (i, (parsed, cue)) in parsed.iter().zip(&cues).enumerate() {
+            assert_eq!(parsed.id, i + 1);
+            assert_eq!(parsed.ms_start, cue.ms_start);
+            assert_eq!(parsed.ms_end, cue.ms_end);
+            assert_eq!(parsed.text, cue.text);
+            assert!(parsed.word_times.is_empty());
+        }
+    }
+
+    // 引用符で囲んだフィールドの中のカンマ、改行、二重にした引用符を戻しながら行に分ける
+    fn parse_csv(csv: &str) -> Vec<Vec<String>> {
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut chars = csv.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => row.push(std::mem::take(&mut field)),
+                '\n' if !quoted => {
+                    row.push(std::mem::take(&mut field));
+                    rows.push(std::mem::take(&mut row));
+                }
+                _ => field.push(c),
+            }
+        }
+        rows
+    }
+
+    #[test]
+    fn csv_escapes_quotes_commas_and_newlines() {
+        let text = "He said \"hi, there\"\nand left";
+        let cues = vec![
+            cue(0, 1_500, text, Vec::new(), "A, \"B\""),
+            cue(3_723_456, 3_725_000, "plain", Vec::new(), ""),
+        ];
+        let rows = parse_csv(&csv_cues(&cues, TimeRange::default()));
+        assert_eq!(rows.len(), 3);
+        assert!(rows.iter().all(|row| row.len() == 8));
+        assert_eq!(rows[1][3], "00:00:00,000");
+        assert_eq!(rows[1][4], "00:00:01,500");
+        assert_eq!(rows[1][5], text);
+        assert_eq!(rows[1][7], "A, \"B\"");
+        assert_eq!(rows[2][3], "01:02:03,456");
+        assert_eq!(rows[2][5], "plain");
+    }
+
+    #[test]
+    fn vtt_word_cues_round_trip() {
+        let words = vec![
+            word(" Hello", 1_000, 1_400),
+            word(" big", 1_400, 1_700),
+            word(" world", 1_700, 2_000),
+        ];
+        let cues = normalize(vec![
+            cue(1_000, 2_000, "Hello big world", words, "A"),
+            cue(2_000, 2_500, "Bye", Vec::new(), "B"),
+        ]);
+        let parsed = parse_vtt(&vtt_cues(&cues, 42, SpeakerTurnMark::Chevron, true));
+        assert_eq!(parsed[0].text, "Hello big world");
+        // 最初の単語はキューの開始と同じ時刻なのでタグを付けない
+        assert_eq!(parsed[0].word_times, vec![1_400, 1_700]);
+        assert_eq!(parsed[1].text, ">> Bye");
+        assert_eq!(parsed[1].ms_start, 2_000);
+        assert_eq!(parsed[1].ms_end, 2_500);
+    }
+}
@@ -2,13 +2,32 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use hound::{SampleFormat, WavSpec};
-use std::path::PathBuf;
-use store::STORE;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use store::{
+    LowConfidenceSegment, ReplaceCount, SamplingStrategyConfig, SearchMatch, SegmentData,
+    SpeakerSegment, Statistics, STORE,
+};
 use tauri::Manager;
 
+mod audio;
 mod audio_conv;
+mod batch;
+mod benchmark;
+mod error;
+mod export;
+mod messages;
+mod microphone;
+mod model_cache;
+mod model_downloader;
+mod model_registry;
+mod models;
+mod queue;
 mod store;
+mod system_info;
 mod whisper;
+mod whisper_log;
 
 #[tauri::command]
 async fn check_wav(path_to_wav: &str) -> Result<(), String> {
@@ -41,6 +60,8 @@ async fn audio_conv(
     audio_conv::run(path_to_media, path_to_wav, &app).await
 }
 
+// offset_msとduration_msは以前のフロントエンドとの互換のために残している（非推奨）。
+// 範囲はset_offset_seconds・set_duration_secondsで指定する
 #[tauri::command]
 async fn whisper(
     path_to_wav: &str,
@@ -50,19 +71,370 @@ async fn whisper(
     offset_ms: i32,
     duration_ms: i32,
     app: tauri::AppHandle,
-) -> Result<(), String> {
-    whisper::run(
+) -> Result<(), whisper::WhisperError> {
+    let result = whisper::run(
         path_to_wav,
         path_to_model,
         lang,
         translate,
         offset_ms,
         duration_ms,
+        whisper::CANCEL_FLAG.clone(),
+        &app,
+    )
+    .await;
+    save_session(&app);
+    // エラーはcodeと中身を持つJSONのままフロントエンドへ返す
+    result
+}
+
+// model_pathを指定したときは、その範囲だけ別のモデルで文字起こしする
+#[tauri::command]
+async fn retranscribe_range(
+    start_ms: i64,
+    end_ms: i64,
+    model_path: Option<String>,
+    app: tauri::AppHandle,
+) -> Result<(), whisper::WhisperError> {
+    let result = whisper::run_range(
+        start_ms,
+        end_ms,
+        model_path.map(PathBuf::from),
+        whisper::CANCEL_FLAG.clone(),
         &app,
     )
+    .await;
+    save_session(&app);
+    result
+}
+
+#[tauri::command]
+async fn set_offset_seconds(offset_s: f64, app: tauri::AppHandle) -> Result<(), String> {
+    STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .set_offset_seconds(&app, offset_s)?;
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_duration_seconds(duration_s: f64, app: tauri::AppHandle) -> Result<(), String> {
+    STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .set_duration_seconds(&app, duration_s)?;
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn batch(paths: Vec<String>, app: tauri::AppHandle) -> Result<(), String> {
+    let result = batch::run_batch(paths, app.clone()).await;
+    save_session(&app);
+    result
+}
+
+#[tauri::command]
+async fn enqueue_files(paths: Vec<String>, app: tauri::AppHandle) -> Result<Vec<u64>, String> {
+    queue::enqueue_files(paths, app)
+}
+
+#[tauri::command]
+async fn get_queue() -> Result<Vec<queue::QueueItem>, String> {
+    Ok(queue::QUEUE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .get_items())
+}
+
+#[tauri::command]
+async fn remove_from_queue(id: u64) -> Result<(), String> {
+    queue::QUEUE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .remove(id)
+}
+
+#[tauri::command]
+async fn cancel_queue_item() -> Result<(), String> {
+    queue::cancel_current();
+    Ok(())
+}
+
+#[tauri::command]
+async fn clear_queue(app: tauri::AppHandle) -> Result<(), String> {
+    queue::clear(&app)
+}
+
+// 診断情報としてコピーできるよう、whisper.cppのログの最後のn行を返す
+#[tauri::command]
+async fn get_recent_logs(n: usize) -> Result<Vec<whisper_log::LogLine>, String> {
+    Ok(whisper_log::recent(n))
+}
+
+#[tauri::command]
+async fn get_system_info() -> Result<system_info::SystemInfo, String> {
+    Ok(system_info::get())
+}
+
+// 取り消しはcancel_transcriptionと共通
+#[tauri::command]
+async fn benchmark_model(
+    model_path: String,
+    duration_s: f64,
+    save: Option<bool>,
+    app: tauri::AppHandle,
+) -> Result<benchmark::BenchmarkResult, String> {
+    benchmark::run(
+        PathBuf::from(model_path),
+        duration_s,
+        save.unwrap_or(false),
+        app,
+    )
     .await
 }
 
+#[tauri::command]
+async fn cancel_transcription() -> Result<(), String> {
+    whisper::CANCEL_FLAG.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+#[tauri::command]
+async fn start_recording(app: tauri::AppHandle) -> Result<(), String> {
+    let mut recording = microphone::RECORDING.lock().unwrap();
+    if recording.is_some() {
+        return Err("すでに録音中です".to_string());
+    }
+    *recording = Some(microphone::start_recording(app.clone())?);
+    whisper::emit_status(&app, "recording", "recording_started");
+    Ok(())
+}
+
+#[tauri::command]
+async fn stop_recording(app: tauri::AppHandle) -> Result<(), String> {
+    let handle = microphone::RECORDING
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| "録音していません".to_string())?;
+    let samples = microphone::stop_recording(handle);
+    let result = whisper::run_samples(samples, whisper::CANCEL_FLAG.clone(), &app).await;
+    save_session(&app);
+    result.map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn start_dictation(app: tauri::AppHandle) -> Result<(), String> {
+    let mut dictation = microphone::DICTATION
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?;
+    if dictation.is_some() {
+        return Err("すでにライブ文字起こし中です".to_string());
+    }
+    *dictation = Some(microphone::start_dictation(app.clone())?);
+    whisper::emit_status(&app, "dictation", "dictation_started");
+    Ok(())
+}
+
+// 残りの音声を文字起こしし終えるまで待ってから戻る
+#[tauri::command]
+async fn stop_dictation(app: tauri::AppHandle) -> Result<(), String> {
+    let handle = microphone::DICTATION
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .take()
+        .ok_or_else(|| "ライブ文字起こしをしていません".to_string())?;
+    microphone::stop_dictation(handle);
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn list_input_devices() -> Result<Vec<String>, String> {
+    Ok(microphone::input_device_names())
+}
+
+#[tauri::command]
+async fn download_model(
+    model: whisper::ModelSize,
+    english_only: Option<bool>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    let dest_dir = models::models_dir(&app).ok_or("保存先のフォルダが見つかりません")?;
+    model_downloader::download_model(model, english_only.unwrap_or(false), dest_dir, &app)
+        .await
+        .map_err(|err| err.to_string())?;
+    model_registry::scan(&app);
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn list_available_models() -> Result<Vec<models::ModelInfo>, String> {
+    let active = STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .get_path_model()
+        .to_path_buf();
+    Ok(model_registry::MODEL_REGISTRY
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .list(&active))
+}
+
+// 言語が英語なら、同じ大きさの英語専用モデルがあればそちらを使う
+#[tauri::command]
+async fn set_active_model(
+    model: whisper::ModelSize,
+    app: tauri::AppHandle,
+) -> Result<PathBuf, String> {
+    let mut store = STORE.lock().map_err(|_| "Mutex is poisoned")?;
+    let english = store.get_lang() == Some("en");
+    let path = model_registry::MODEL_REGISTRY
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .path_for(model, english)
+        .map(Path::to_path_buf)
+        .ok_or_else(|| format!("モデルが見つかりません: {:?}", model))?;
+    let changed = store
+        .set_path_model(&app, path.clone())
+        .map_err(|err| err.to_string())?;
+    drop(store);
+    if changed {
+        model_cache::MODEL_CACHE.invalidate();
+    }
+    save_session(&app);
+    Ok(path)
+}
+
+#[tauri::command]
+async fn cancel_model_download() -> Result<(), String> {
+    whisper::CANCEL_FLAG.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+// 文字起こしは別スレッドで行うので、実行中でもすぐに返る
+#[tauri::command]
+async fn is_transcribing() -> bool {
+    model_cache::MODEL_CACHE.is_running()
+}
+
+#[tauri::command]
+async fn warm_model() -> Result<(), String> {
+    let (path_model, gpu) = {
+        let store = STORE.lock().map_err(|_| "Mutex is poisoned")?;
+        (
+            store.get_path_model().to_path_buf(),
+            store.get_gpu_settings(),
+        )
+    };
+    models::validate(&path_model, false).map_err(|err| err.to_string())?;
+    model_cache::MODEL_CACHE.get(&path_model, gpu).map(|_| ())
+}
+
+#[tauri::command]
+async fn unload_model(app: tauri::AppHandle) -> Result<u64, String> {
+    model_cache::MODEL_CACHE.unload(&app, "manual")
+}
+
+#[tauri::command]
+async fn list_models(app: tauri::AppHandle) -> Result<Vec<models::ModelInfo>, String> {
+    models::list_models(&app)
+}
+
+#[tauri::command]
+async fn validate_model(path: Option<String>, verify_hash: Option<bool>) -> Result<(), String> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => STORE
+            .lock()
+            .map_err(|_| "Mutex is poisoned")?
+            .get_path_model()
+            .to_path_buf(),
+    };
+    models::validate(&path, verify_hash.unwrap_or(false)).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn export_srt(path: String, app: tauri::AppHandle) -> Result<(), String> {
+    export::export_srt(Path::new(&path)).map_err(|err| err.to_string())?;
+    whisper::emit_status(&app, "exported", "subtitles_exported");
+    Ok(())
+}
+
+#[tauri::command]
+async fn export_vtt(
+    path: String,
+    word_cues: Option<bool>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    export::export_vtt(Path::new(&path), word_cues.unwrap_or(false))
+        .map_err(|err| err.to_string())?;
+    whisper::emit_status(&app, "exported", "subtitles_exported");
+    Ok(())
+}
+
+#[tauri::command]
+async fn export_json(
+    path: String,
+    range: Option<export::TimeRange>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    export::export_json(Path::new(&path), range.unwrap_or_default())
+        .map_err(|err| err.to_string())?;
+    whisper::emit_status(&app, "exported", "transcript_exported");
+    Ok(())
+}
+
+// 読み込み直せる形式で、全セグメントと主な設定を書き出す
+#[tauri::command]
+async fn export_transcript_json(path: String, app: tauri::AppHandle) -> Result<(), String> {
+    export::export_json(Path::new(&path), export::TimeRange::default())
+        .map_err(|err| err.to_string())?;
+    whisper::emit_status(&app, "exported", "transcript_exported");
+    Ok(())
+}
+
+#[tauri::command]
+async fn import_transcript_json(path: String, app: tauri::AppHandle) -> Result<usize, String> {
+    let count = export::import_json(&app, Path::new(&path)).map_err(|err| err.to_string())?;
+    save_session(&app);
+    Ok(count)
+}
+
+#[tauri::command]
+async fn export_csv(
+    path: String,
+    range: Option<export::TimeRange>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    export::export_csv(Path::new(&path), range.unwrap_or_default())
+        .map_err(|err| err.to_string())?;
+    whisper::emit_status(&app, "exported", "transcript_exported");
+    Ok(())
+}
+
+#[tauri::command]
+async fn export_txt(path: String, separator: String, app: tauri::AppHandle) -> Result<(), String> {
+    export::export_txt(Path::new(&path), separator.parse()?).map_err(|err| err.to_string())?;
+    whisper::emit_status(&app, "exported", "transcript_exported");
+    Ok(())
+}
+
+#[tauri::command]
+async fn export_ass(
+    path: String,
+    style: Option<export::AssStyle>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    export::export_ass(Path::new(&path), &style.unwrap_or_default())
+        .map_err(|err| err.to_string())?;
+    whisper::emit_status(&app, "exported", "subtitles_exported");
+    Ok(())
+}
+
 #[tauri::command]
 async fn refresh_config(
     param_name: String,
@@ -70,18 +442,502 @@ async fn refresh_config(
     app: tauri::AppHandle,
 ) -> Result<(), String> {
     let mut config = STORE.lock().unwrap();
+    // 読み込み済みのモデルが使えなくなったら、STOREを放してからキャッシュを捨てる
+    let mut model_changed = false;
     match param_name.as_str() {
         "pathWav" => config.set_path_wav(&app, PathBuf::from(param_data)),
-        "pathModel" => config.set_path_model(&app, PathBuf::from(param_data)),
+        "pathModel" => {
+            model_changed = config
+                .set_path_model(&app, PathBuf::from(param_data))
+                .map_err(|err| err.to_string())?
+        }
+        "modelsDir" => config.set_models_dir(
+            &app,
+            Some(PathBuf::from(param_data)).filter(|dir| !dir.as_os_str().is_empty()),
+        ),
         "lang" => config.set_lang(&app, param_data),
         "translate" => config.set_translate(&app, param_data.parse().unwrap_or_default()),
         "secStart" => config.set_sec_start(&app, param_data.parse().unwrap_or_default()),
         "secEnd" => config.set_sec_end(&app, param_data.parse().unwrap_or_default()),
+        "progressInterval" => config.set_progress_interval(&app, param_data.parse().unwrap_or(1)),
+        "channel" => config.set_channel(&app, param_data.parse().ok()),
+        "audioTrack" => config.set_audio_track(&app, param_data.parse().unwrap_or_default()),
+        "speakerTurnMark" => config.set_speaker_turn_mark(&app, param_data.parse()?),
+        "lineLength" => config.set_line_length(&app, param_data.parse().unwrap_or(42)),
+        "inputDevice" => {
+            config.set_input_device_name(&app, Some(param_data).filter(|name| !name.is_empty()))
+        }
+        "nThreads" => config.set_n_threads(&app, param_data.parse().ok().filter(|n| *n > 0)),
+        // カンマ区切りのトークンID。空文字なら既定の抑制リストも外す
+        "suppressTokens" => config.set_suppress_tokens(
+            &app,
+            param_data
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(|id| parse_param(&param_name, id))
+                .collect::<Result<_, _>>()?,
+        ),
+        "suppressBlank" => config.set_suppress_blank(&app, param_data.parse().unwrap_or(true)),
+        "wordTimestamps" => {
+            config.set_word_timestamps(&app, param_data.parse().unwrap_or_default())
+        }
+        "maxSegmentChars" => config.set_max_segment_chars(
+            &app,
+            param_data.parse().ok().filter(|max_chars| *max_chars > 0),
+        ),
+        "modelIdleTimeout" => {
+            config.set_model_idle_timeout_min(&app, param_data.parse().ok().filter(|min| *min > 0))
+        }
+        "maxLen" => config.set_max_len(&app, param_data.parse().unwrap_or_default()),
+        "splitOnWord" => config.set_split_on_word(&app, param_data.parse().unwrap_or(true)),
+        "maxContextTokens" => {
+            config.set_max_context_tokens(&app, parse_param(&param_name, &param_data)?)?
+        }
+        // 空か0なら音声の長さから自動で決める
+        "audioCtx" => config.set_audio_ctx(&app, param_data.parse().ok().filter(|ctx| *ctx > 0))?,
+        "temperature" => config.set_temperature(&app, parse_param(&param_name, &param_data)?)?,
+        "temperatureInc" => {
+            config.set_temperature_inc(&app, parse_param(&param_name, &param_data)?)?
+        }
+        "entropyThold" => config.set_entropy_thold(&app, parse_param(&param_name, &param_data)?)?,
+        "logprobThold" => config.set_logprob_thold(&app, parse_param(&param_name, &param_data)?)?,
+        "noSpeechThold" => {
+            config.set_no_speech_thold(&app, parse_param(&param_name, &param_data)?)?
+        }
+        "langDetectMinProb" => {
+            config.set_lang_detect_min_prob(&app, parse_param(&param_name, &param_data)?)?
+        }
+        "repeatFilter" => config.set_repeat_filter(&app, param_data.parse()?),
+        "uiLang" => config.set_ui_lang(&app, param_data.parse()?),
+        "repeatWindow" => {
+            config.set_repeat_limits(&app, Some(parse_param(&param_name, &param_data)?), None)?
+        }
+        "repeatThreshold" => {
+            config.set_repeat_limits(&app, None, Some(parse_param(&param_name, &param_data)?))?
+        }
+        "vad" => {
+            let vad = parse_param(&param_name, &param_data)?;
+            config.set_vad(&app, vad, None)
+        }
+        "metrics" => config.set_metrics(&app, parse_param(&param_name, &param_data)?),
+        "useGpu" => {
+            model_changed = config.set_use_gpu(&app, parse_param(&param_name, &param_data)?)
+        }
+        "gpuDevice" => {
+            model_changed = config.set_gpu_device(&app, parse_param(&param_name, &param_data)?)?
+        }
+        "gapMerge" => config.set_gap_merge_ms(&app, param_data.parse().ok().filter(|ms| *ms > 0)),
+        "vadMinSilence" => {
+            let vad = config.get_vad().is_some();
+            config.set_vad(&app, vad, Some(parse_param(&param_name, &param_data)?))
+        }
+        "fallbackLang" => {
+            config.set_fallback_lang(&app, Some(param_data).filter(|lang| !lang.is_empty()))
+        }
         _ => (),
     }
+    drop(config);
+    if model_changed {
+        model_cache::MODEL_CACHE.invalidate();
+    }
+    if param_name == "modelsDir" {
+        model_registry::scan(&app);
+    }
+    save_session(&app);
     Ok(())
 }
 
+#[tauri::command]
+async fn clear_store(keep_config: Option<bool>, app: tauri::AppHandle) -> Result<(), String> {
+    STORE
+        .lock()
+        .unwrap()
+        .clear(&app, keep_config.unwrap_or(false));
+    for path in [store::session_path(&app), store::autosave_path(&app)]
+        .into_iter()
+        .flatten()
+    {
+        if path.exists() {
+            std::fs::remove_file(&path).map_err(|err| err.to_string())?;
+        }
+    }
+    whisper::emit_status(&app, "cleared", "results_cleared");
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_sampling_strategy(
+    config: SamplingStrategyConfig,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    // モデルを読み込む前に不正な組み合わせを弾いておく
+    config.validate()?;
+    STORE.lock().unwrap().set_sampling_strategy(&app, config);
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_whisper_prompt(text: String, app: tauri::AppHandle) -> Result<(), String> {
+    set_initial_prompt(text, app).await
+}
+
+#[tauri::command]
+async fn set_initial_prompt(prompt: String, app: tauri::AppHandle) -> Result<(), String> {
+    STORE.lock().unwrap().set_initial_prompt(&app, Some(prompt));
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn clear_initial_prompt(app: tauri::AppHandle) -> Result<(), String> {
+    STORE.lock().unwrap().set_initial_prompt(&app, None);
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn segments_below_confidence(threshold: f32) -> Result<Vec<usize>, String> {
+    Ok(STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .segments_below_confidence(threshold))
+}
+
+#[tauri::command]
+async fn get_low_confidence_segments(threshold: f32) -> Result<Vec<LowConfidenceSegment>, String> {
+    Ok(STORE.lock().unwrap().get_low_confidence_segments(threshold))
+}
+
+#[tauri::command]
+async fn update_segment(index: usize, text: String, app: tauri::AppHandle) -> Result<(), String> {
+    let segment = {
+        let mut store = STORE.lock().map_err(|_| "Mutex is poisoned")?;
+        store.update_segment(&app, index, text)?;
+        store.get_segment(index)
+    };
+    if let Some(segment) = segment {
+        whisper::emit_segment_updated(&app, segment);
+    }
+    save_session(&app);
+    Ok(())
+}
+
+// タイムコードを手で直して順番が前後したときに使う。並べ替えたらtrueを返す
+#[tauri::command]
+async fn reorder_segments(app: tauri::AppHandle) -> Result<bool, String> {
+    let reordered = STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .reorder_segments(&app);
+    save_session(&app);
+    Ok(reordered)
+}
+
+#[tauri::command]
+async fn update_segment_timing(
+    index: usize,
+    t0: i64,
+    t1: i64,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    let segment = {
+        let mut store = STORE.lock().map_err(|_| "Mutex is poisoned")?;
+        store.update_segment_timing(&app, index, t0, t1)?;
+        store.get_segment(index)
+    };
+    if let Some(segment) = segment {
+        whisper::emit_segment_updated(&app, segment);
+    }
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn merge_segments(a: usize, b: usize, app: tauri::AppHandle) -> Result<(), String> {
+    STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .merge_segments(&app, a, b)?;
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn merge_by_gap(max_gap_ms: u64, app: tauri::AppHandle) -> Result<usize, String> {
+    let count = STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .merge_by_gap(&app, max_gap_ms);
+    save_session(&app);
+    Ok(count)
+}
+
+#[tauri::command]
+async fn split_segment(
+    index: usize,
+    offset: usize,
+    at_ms: Option<i64>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .split_segment(&app, index, offset, at_ms)?;
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn shift_segments(
+    delta_ms: i64,
+    range: Option<(i64, i64)>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .shift_segments(&app, delta_ms, range)?;
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn undo(app: tauri::AppHandle) -> Result<(), String> {
+    STORE.lock().map_err(|_| "Mutex is poisoned")?.undo(&app)?;
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn redo(app: tauri::AppHandle) -> Result<(), String> {
+    STORE.lock().map_err(|_| "Mutex is poisoned")?.redo(&app)?;
+    save_session(&app);
+    Ok(())
+}
+
+#[tauri::command]
+async fn search_segments(
+    query: String,
+    case_sensitive: bool,
+    regex: bool,
+) -> Result<Vec<SearchMatch>, String> {
+    STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .search_segments(&query, case_sensitive, regex)
+}
+
+#[tauri::command]
+async fn get_statistics() -> Result<Statistics, String> {
+    Ok(STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .get_statistics())
+}
+
+#[tauri::command]
+async fn find_segments(query: String) -> Result<Vec<usize>, String> {
+    Ok(STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .find_segments(&query))
+}
+
+#[tauri::command]
+async fn find_and_replace(
+    query: String,
+    replacement: String,
+    app: tauri::AppHandle,
+) -> Result<usize, String> {
+    let count = STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .find_and_replace(&app, &query, &replacement)?;
+    save_session(&app);
+    Ok(count)
+}
+
+#[tauri::command]
+async fn replace_in_segments(
+    query: String,
+    replacement: String,
+    regex: bool,
+    range: Option<(i64, i64)>,
+    app: tauri::AppHandle,
+) -> Result<Vec<ReplaceCount>, String> {
+    let counts = STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .replace_in_segments(&app, &query, &replacement, regex, range)?;
+    save_session(&app);
+    Ok(counts)
+}
+
+#[tauri::command]
+async fn get_segments() -> Result<Vec<SegmentData>, String> {
+    Ok(STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .get_all_segments())
+}
+
+#[tauri::command]
+async fn get_segments_with_speakers() -> Result<Vec<SpeakerSegment>, String> {
+    Ok(STORE.lock().unwrap().get_segments_with_speakers())
+}
+
+#[tauri::command]
+async fn list_whisper_languages() -> Result<Vec<whisper::WhisperLanguage>, String> {
+    let lang = STORE.lock().unwrap().get_lang().unwrap_or("ja").to_string();
+    Ok(whisper::languages(&lang))
+}
+
+#[tauri::command]
+async fn estimate_transcription_time(
+    audio_path: String,
+    model_path: String,
+) -> Result<u64, String> {
+    let model_size = models::parse_name(Path::new(&model_path))
+        .size
+        .ok_or_else(|| format!("モデルの大きさがファイル名から分かりません: {}", model_path))?;
+    let audio_duration_ms =
+        audio::wav_duration_ms(Path::new(&audio_path)).map_err(|err| err.to_string())?;
+    Ok(whisper::estimate_duration_ms(audio_duration_ms, model_size))
+}
+
+#[tauri::command]
+async fn get_cpu_count() -> Result<u32, String> {
+    Ok(num_cpus::get_physical() as u32)
+}
+
+#[tauri::command]
+async fn get_decoding_settings() -> Result<SamplingStrategyConfig, String> {
+    Ok(STORE.lock().unwrap().get_sampling_strategy())
+}
+
+fn parse_param<T: FromStr>(param_name: &str, param_data: &str) -> Result<T, String> {
+    param_data
+        .parse()
+        .map_err(|_| format!("{}の値が不正です: {}", param_name, param_data))
+}
+
+#[tauri::command]
+async fn save_project(path: String) -> Result<std::path::PathBuf, String> {
+    let mut path = std::path::PathBuf::from(path);
+    if path
+        .extension()
+        .is_none_or(|ext| ext != store::PROJECT_EXTENSION)
+    {
+        path.as_mut_os_string()
+            .push(format!(".{}", store::PROJECT_EXTENSION));
+    }
+    STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .save(&path)
+        .map_err(|err| format!("プロジェクトを保存できませんでした: {}", err))?;
+    Ok(path)
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct OpenProjectPayload {
+    num_data: usize,
+    audio_missing: bool,
+}
+
+// 音声ファイルが見つからなくても結果は読み込み、audio_missingで知らせる
+#[tauri::command]
+async fn open_project(path: String, app: tauri::AppHandle) -> Result<OpenProjectPayload, String> {
+    let project = store::Store::load(std::path::Path::new(&path)).map_err(|err| err.to_string())?;
+    let payload = OpenProjectPayload {
+        num_data: project.num_data(),
+        audio_missing: project.is_audio_missing(),
+    };
+    STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .restore(&app, project);
+    save_session(&app);
+    if payload.audio_missing {
+        whisper::emit_status(&app, "audio_missing", "audio_missing");
+    }
+    whisper::emit_status_with(
+        &app,
+        "restored",
+        "project_opened",
+        serde_json::json!({ "num_data": payload.num_data }),
+    );
+    Ok(payload)
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct RecoverSessionPayload {
+    available: bool,
+    num_data: usize,
+}
+
+// 自動保存がsession.jsonより新しければ、前回は保存する前に落ちている。
+// loadを指定したときは自動保存の内容に差し替える
+#[tauri::command]
+async fn recover_session(
+    load: Option<bool>,
+    app: tauri::AppHandle,
+) -> Result<RecoverSessionPayload, String> {
+    let modified = |path: Option<std::path::PathBuf>| {
+        path.and_then(|path| std::fs::metadata(path).ok())
+            .and_then(|meta| meta.modified().ok())
+    };
+    let Some(path_autosave) = store::autosave_path(&app) else {
+        return Ok(RecoverSessionPayload {
+            available: false,
+            num_data: 0,
+        });
+    };
+    let available = match (
+        modified(Some(path_autosave.clone())),
+        modified(store::session_path(&app)),
+    ) {
+        (Some(autosaved), Some(saved)) => autosaved > saved,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    if !available {
+        return Ok(RecoverSessionPayload {
+            available,
+            num_data: 0,
+        });
+    }
+    let recovered = store::Store::load(&path_autosave).map_err(|err| err.to_string())?;
+    let num_data = recovered.num_data();
+    if load.unwrap_or(false) {
+        STORE
+            .lock()
+            .map_err(|_| "Mutex is poisoned")?
+            .restore(&app, recovered);
+        save_session(&app);
+        whisper::emit_status_with(
+            &app,
+            "restored",
+            "autosave_restored",
+            serde_json::json!({ "num_data": num_data }),
+        );
+    }
+    Ok(RecoverSessionPayload {
+        available,
+        num_data,
+    })
+}
+
+fn save_session(app: &tauri::AppHandle) {
+    if let Some(path) = store::session_path(app) {
+        if let Err(err) = STORE.lock().unwrap().save_if_dirty(&path) {
+            tracing::warn!("failed to save session: {}", err);
+        }
+    }
+}
+
 fn main() {
     tracing_subscriber::fmt::init();
     tauri::Builder::default()
@@ -89,9 +945,91 @@ fn main() {
             check_wav,
             audio_conv,
             whisper,
-            refresh_config
+            retranscribe_range,
+            set_offset_seconds,
+            set_duration_seconds,
+            batch,
+            cancel_transcription,
+            enqueue_files,
+            get_queue,
+            remove_from_queue,
+            cancel_queue_item,
+            clear_queue,
+            start_recording,
+            stop_recording,
+            start_dictation,
+            stop_dictation,
+            list_input_devices,
+            download_model,
+            cancel_model_download,
+            list_models,
+            list_available_models,
+            set_active_model,
+            warm_model,
+            is_transcribing,
+            unload_model,
+            validate_model,
+            export_srt,
+            export_vtt,
+            export_ass,
+            export_txt,
+            export_json,
+            export_transcript_json,
+            import_transcript_json,
+            export_csv,
+            refresh_config,
+            clear_store,
+            recover_session,
+            save_project,
+            open_project,
+            set_sampling_strategy,
+            set_whisper_prompt,
+            set_initial_prompt,
+            clear_initial_prompt,
+            get_low_confidence_segments,
+            segments_below_confidence,
+            get_segments,
+            search_segments,
+            find_segments,
+            get_statistics,
+            find_and_replace,
+            replace_in_segments,
+            update_segment,
+            update_segment_timing,
+            reorder_segments,
+            merge_segments,
+            merge_by_gap,
+            split_segment,
+            shift_segments,
+            undo,
+            redo,
+            get_segments_with_speakers,
+            list_whisper_languages,
+            get_cpu_count,
+            estimate_transcription_time,
+            get_decoding_settings,
+            get_recent_logs,
+            get_system_info,
+            benchmark_model
         ])
         .setup(|app| {
+            whisper_log::install(app.handle());
+            if let Some(path) = store::session_path(&app.handle()) {
+                if let Ok(restored) = store::Store::load(&path) {
+                    let num_data = restored.num_data();
+                    messages::set_ui_lang(restored.get_ui_lang());
+                    *STORE.lock().unwrap() = restored;
+                    whisper::emit_status_with(
+                        &app.handle(),
+                        "restored",
+                        "session_restored",
+                        serde_json::json!({ "num_data": num_data }),
+                    );
+                }
+            }
+            model_cache::start_idle_unloader(app.handle());
+            model_registry::scan(&app.handle());
+            store::start_autosave(app.handle());
             #[cfg(debug_assertions)] // only include this code on debug builds
             {
                 let window = app.get_window("main").unwrap();
@@ -100,6 +1038,15 @@ fn main() {
             }
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            // 正常に終了したときは自動保存を残さない
+            if let tauri::RunEvent::Exit = event {
+                save_session(app);
+                if let Some(path) = store::autosave_path(app) {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        });
 }
@@ -0,0 +1,141 @@
+use once_cell::sync::Lazy;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+// イベントのmessageに使う言語。フロントエンドはcodeとparamsから自分で表示してもよい
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UiLang {
+    Ja,
+    En,
+}
+
+impl FromStr for UiLang {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ja" => Ok(UiLang::Ja),
+            "en" => Ok(UiLang::En),
+            _ => Err(format!("表示言語の指定が不正です: {}", s)),
+        }
+    }
+}
+
+// STOREをロックしたまま通知することがあるので、設定の言語はここにも持っておく
+static UI_LANG: Lazy<Mutex<UiLang>> = Lazy::new(|| Mutex::new(UiLang::Ja));
+
+pub fn set_ui_lang(lang: UiLang) {
+    if let Ok(mut ui_lang) = UI_LANG.lock() {
+        *ui_lang = lang;
+    }
+}
+
+// codeごとの(日本語, 英語)の文。{name}はparamsのnameの値に置き換える
+fn templates(code: &str) -> Option<(&'static str, &'static str)> {
+    Some(match code {
+        "recording_started" => ("録音を開始しました", "Recording started"),
+        "dictation_started" => (
+            "ライブ文字起こしを開始しました",
+            "Live transcription started",
+        ),
+        "dictation_stopped" => (
+            "ライブ文字起こしを終了しました",
+            "Live transcription stopped",
+        ),
+        "input_device_disconnected" => (
+            "入力デバイスが切断されました",
+            "The input device was disconnected",
+        ),
+        "subtitles_exported" => ("字幕ファイルを書き出しました", "Subtitles exported"),
+        "transcript_exported" => ("文字起こし結果を書き出しました", "Transcript exported"),
+        "results_cleared" => ("文字起こし結果を消去しました", "Transcript cleared"),
+        "audio_missing" => (
+            "音声ファイルが見つからないため、文字起こしし直すことはできません",
+            "The audio file was not found, so the project cannot be transcribed again",
+        ),
+        "session_restored" => (
+            "前回の文字起こし結果({num_data}件)を復元しました",
+            "Restored the previous transcript ({num_data} segments)",
+        ),
+        "project_opened" => (
+            "プロジェクト({num_data}件)を開きました",
+            "Opened the project ({num_data} segments)",
+        ),
+        "autosave_restored" => (
+            "自動保存した文字起こし結果({num_data}件)を復元しました",
+            "Restored the autosaved transcript ({num_data} segments)",
+        ),
+        "resampling" => (
+            "音声を16kHzモノラルに変換しています",
+            "Converting the audio to 16 kHz mono",
+        ),
+        "prompt_truncated" => (
+            "初期プロンプトが長すぎるため、末尾の{max_tokens}トークンだけを使います",
+            "The initial prompt is too long; only the last {max_tokens} tokens are used",
+        ),
+        "language_detected" => ("{lang}", "{lang}"),
+        "transcription_started" => (
+            "初期化が完了しました。文字起こしを開始します。",
+            "Initialization finished. Starting transcription.",
+        ),
+        "gpu_fallback" => (
+            "GPUでモデルを読み込めなかったため、CPUで文字起こしします",
+            "Could not load the model on the GPU; transcribing on the CPU instead",
+        ),
+        "cancelled" => ("文字起こしを中止しました", "Transcription cancelled"),
+        // WhisperErrorのcode
+        "poisoned" => ("Mutex is poisoned", "Mutex is poisoned"),
+        "thread" => (
+            "文字起こしのスレッドが異常終了しました",
+            "The transcription thread terminated unexpectedly",
+        ),
+        "config" => ("{message}", "The settings are invalid: {message}"),
+        "wav_open" => ("{message}", "Could not open the audio file"),
+        "wav_decode" => ("{message}", "Could not decode the audio file"),
+        "audio_invalid_format" => (
+            "{message}",
+            "Unsupported audio format: expected {expected}, got {got}",
+        ),
+        "model_load" => ("{message}", "Could not load the model: {path}"),
+        "state_init" => (
+            "Whisper Stateの初期化に失敗しました",
+            "Failed to initialize the Whisper state",
+        ),
+        "language_detect" => ("{message}", "Could not detect the language"),
+        "language_uncertain" => (
+            "言語を判定できませんでした。言語を指定してください",
+            "Could not detect the language. Please select a language",
+        ),
+        "inference" => ("言語モデルの実行に失敗しました", "Failed to run the model"),
+        "segment_text" => (
+            "Text segment could not be converted to string.",
+            "Text segment could not be converted to string.",
+        ),
+        "event_emit" => ("イベントの送信に失敗しました", "Failed to send an event"),
+        "export" => ("{message}", "Could not write the subtitles: {message}"),
+        _ => return None,
+    })
+}
+
+// 設定の言語でcodeの文を作る。知らないcodeならcodeをそのまま返す
+pub fn text(code: &str, params: &serde_json::Value) -> String {
+    let Some((ja, en)) = templates(code) else {
+        return code.to_string();
+    };
+    let template = match UI_LANG.lock().map_or(UiLang::Ja, |lang| *lang) {
+        UiLang::Ja => ja,
+        UiLang::En => en,
+    };
+    let mut text = template.to_string();
+    if let Some(params) = params.as_object() {
+        for (name, value) in params {
+            let value = match value.as_str() {
+                Some(value) => value.to_string(),
+                None => value.to_string(),
+            };
+            text = text.replace(&format!("{{{}}}", name), &value);
+        }
+    }
+    text
+}
@@ -0,0 +1,254 @@
+use crate::audio;
+use crate::store::STORE;
+use crate::whisper;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SizedSample};
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tauri::Manager;
+
+const LEVEL_INTERVAL: Duration = Duration::from_millis(50);
+// ライブ文字起こしで16kHzに変換して渡す間隔。短すぎるとリサンプリングの継ぎ目が増える
+const DICTATION_CHUNK: Duration = Duration::from_millis(500);
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct RecordingLevelPayload {
+    level: f32,
+}
+
+pub struct RecordingHandle {
+    buffer: Arc<Mutex<Vec<f32>>>,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+    channels: usize,
+    sample_rate: u32,
+}
+
+// cpal::StreamはSendではないので、ストリームは録音用スレッドの中だけで扱う
+pub fn start_recording(app: tauri::AppHandle) -> Result<RecordingHandle, String> {
+    let device_name = STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .get_input_device_name()
+        .map(String::from);
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+
+    let thread = {
+        let buffer = buffer.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let failed = Arc::new(AtomicBool::new(false));
+            let stream = match open_stream(device_name.as_deref(), buffer.clone(), failed.clone()) {
+                Ok((stream, channels, sample_rate)) => {
+                    let _ = tx.send(Ok((channels, sample_rate)));
+                    stream
+                }
+                Err(err) => {
+                    let _ = tx.send(Err(err));
+                    return;
+                }
+            };
+            let mut last = 0;
+            while !stop.load(Ordering::SeqCst) {
+                thread::sleep(LEVEL_INTERVAL);
+                if failed.load(Ordering::SeqCst) {
+                    whisper::emit_status(&app, "error", "input_device_disconnected");
+                    break;
+                }
+                let level = {
+                    let buffer = buffer.lock().unwrap();
+                    let level = rms(&buffer[last..]);
+                    last = buffer.len();
+                    level
+                };
+                let _ = app.emit_all("recording_level", RecordingLevelPayload { level });
+            }
+            drop(stream);
+        })
+    };
+
+    let (channels, sample_rate) = rx
+        .recv()
+        .map_err(|_| "録音の開始に失敗しました".to_string())??;
+    Ok(RecordingHandle {
+        buffer,
+        stop,
+        thread,
+        channels,
+        sample_rate,
+    })
+}
+
+pub struct DictationHandle {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+// 録音しながらwhisper::run_streamへ音声を送る。入力デバイスが外れたらエラーを送って止まる
+pub fn start_dictation(app: tauri::AppHandle) -> Result<DictationHandle, String> {
+    let device_name = STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .get_input_device_name()
+        .map(String::from);
+    let stop = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+
+    let thread = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let buffer = Arc::new(Mutex::new(Vec::new()));
+            let failed = Arc::new(AtomicBool::new(false));
+            let (stream, channels, sample_rate) =
+                match open_stream(device_name.as_deref(), buffer.clone(), failed.clone()) {
+                    Ok(opened) => {
+                        let _ = tx.send(Ok(()));
+                        opened
+                    }
+                    Err(err) => {
+                        let _ = tx.send(Err(err));
+                        return;
+                    }
+                };
+            let (audio_tx, audio_rx) = mpsc::channel();
+            let worker = {
+                let app = app.clone();
+                thread::spawn(move || whisper::run_stream(audio_rx, app))
+            };
+            let mut pending = Vec::new();
+            let mut last_chunk = Instant::now();
+            while !stop.load(Ordering::SeqCst) {
+                thread::sleep(LEVEL_INTERVAL);
+                if failed.load(Ordering::SeqCst) {
+                    whisper::emit_status(&app, "error", "input_device_disconnected");
+                    break;
+                }
+                let samples = std::mem::take(&mut *buffer.lock().unwrap());
+                let _ = app.emit_all(
+                    "recording_level",
+                    RecordingLevelPayload {
+                        level: rms(&samples),
+                    },
+                );
+                pending.extend(samples);
+                if last_chunk.elapsed() >= DICTATION_CHUNK {
+                    last_chunk = Instant::now();
+                    let chunk = std::mem::take(&mut pending);
+                    match audio::to_mono_16k(chunk, channels, sample_rate) {
+                        Ok(chunk) => {
+                            if audio_tx.send(chunk).is_err() {
+                                break;
+                            }
+                        }
+                        Err(err) => tracing::error!("failed to convert recorded audio: {}", err),
+                    }
+                }
+            }
+            drop(stream);
+            // 送信側を閉じると、run_streamは残りの音声を文字起こしして終わる
+            drop(audio_tx);
+            if let Ok(Err(err)) = worker.join() {
+                tracing::warn!("dictation stopped: {}", err);
+            }
+            whisper::emit_status(&app, "dictation_stopped", "dictation_stopped");
+        })
+    };
+
+    rx.recv()
+        .map_err(|_| "録音の開始に失敗しました".to_string())??;
+    Ok(DictationHandle { stop, thread })
+}
+
+pub fn stop_dictation(handle: DictationHandle) {
+    handle.stop.store(true, Ordering::SeqCst);
+    let _ = handle.thread.join();
+}
+
+pub fn stop_recording(handle: RecordingHandle) -> Vec<f32> {
+    handle.stop.store(true, Ordering::SeqCst);
+    let _ = handle.thread.join();
+    let samples = std::mem::take(&mut *handle.buffer.lock().unwrap());
+    audio::to_mono_16k(samples, handle.channels, handle.sample_rate).unwrap_or_else(|err| {
+        tracing::error!("failed to convert recorded audio: {}", err);
+        Vec::new()
+    })
+}
+
+pub fn input_device_names() -> Vec<String> {
+    cpal::default_host()
+        .input_devices()
+        .map(|devices| devices.filter_map(|device| device.name().ok()).collect())
+        .unwrap_or_default()
+}
+
+fn open_stream(
+    device_name: Option<&str>,
+    buffer: Arc<Mutex<Vec<f32>>>,
+    failed: Arc<AtomicBool>,
+) -> Result<(cpal::Stream, usize, u32), String> {
+    let host = cpal::default_host();
+    let device = match device_name {
+        Some(name) => host
+            .input_devices()
+            .map_err(|err| err.to_string())?
+            .find(|device| device.name().is_ok_and(|n| n == name)),
+        None => host.default_input_device(),
+    }
+    .ok_or_else(|| "入力デバイスが見つかりません".to_string())?;
+    let supported = device
+        .default_input_config()
+        .map_err(|err| err.to_string())?;
+    let config = supported.config();
+    let stream = match supported.sample_format() {
+        cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, buffer, failed),
+        cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, buffer, failed),
+        cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, buffer, failed),
+        cpal::SampleFormat::I32 => build_stream::<i32>(&device, &config, buffer, failed),
+        format => return Err(format!("対応していない入力形式です: {:?}", format)),
+    }
+    .map_err(|err| err.to_string())?;
+    stream.play().map_err(|err| err.to_string())?;
+    Ok((stream, config.channels as usize, config.sample_rate.0))
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    buffer: Arc<Mutex<Vec<f32>>>,
+    failed: Arc<AtomicBool>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            buffer
+                .lock()
+                .unwrap()
+                .extend(data.iter().map(|sample| sample.to_sample::<f32>()));
+        },
+        // デバイスが外れたときなどはここに来るので、録音側のループで止める
+        move |err| {
+            tracing::error!("input stream error: {}", err);
+            failed.store(true, Ordering::SeqCst);
+        },
+        None,
+    )
+}
+
+fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.;
+    }
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+pub static RECORDING: Lazy<Mutex<Option<RecordingHandle>>> = Lazy::new(|| Mutex::new(None));
+pub static DICTATION: Lazy<Mutex<Option<DictationHandle>>> = Lazy::new(|| Mutex::new(None));
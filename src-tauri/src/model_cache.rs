@@ -0,0 +1,205 @@
+use crate::store::STORE;
+use crate::system_info;
+use crate::whisper_log;
+use once_cell::sync::Lazy;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::Manager;
+use whisper_rs::{WhisperContext, WhisperContextParameters};
+
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct ModelUnloadedPayload {
+    reason: String,
+    path: PathBuf,
+    freed_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpuSettings {
+    pub use_gpu: bool,
+    pub gpu_device: i32,
+}
+
+#[derive(Clone)]
+pub struct LoadedModel {
+    pub context: Arc<WhisperContext>,
+    // whisperが実際に初期化したバックエンド（"CPU"、"CUDA"、"Metal"など）
+    pub backend: String,
+    // GPUでの読み込みに失敗して、CPUで読み込み直した
+    pub gpu_fallback: bool,
+}
+
+// 同じモデルで続けて文字起こしするときにコンテキストを作り直さないためのキャッシュ
+pub struct ModelCache {
+    cached: Mutex<Option<(PathBuf, GpuSettings, LoadedModel)>>,
+    // 同じコンテキストで同時にfullを呼ばないよう、文字起こしは1件ずつ行う
+    running: Mutex<()>,
+    last_used: Mutex<Instant>,
+}
+
+// 文字起こしが終わった時点を最後に使った時刻として記録する
+pub struct RunGuard<'a> {
+    _running: MutexGuard<'a, ()>,
+    last_used: &'a Mutex<Instant>,
+}
+
+impl Drop for RunGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut last_used) = self.last_used.lock() {
+            *last_used = Instant::now();
+        }
+    }
+}
+
+impl ModelCache {
+    fn new() -> ModelCache {
+        ModelCache {
+            cached: Mutex::new(None),
+            running: Mutex::new(()),
+            last_used: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn get(&self, path_model: &Path, gpu: GpuSettings) -> Result<LoadedModel, String> {
+        let mut cached = self.cached.lock().map_err(|_| "Mutex is poisoned")?;
+        if let Some((path, cached_gpu, loaded)) = cached.as_ref() {
+            if path == path_model && *cached_gpu == gpu {
+                return Ok(loaded.clone());
+            }
+        }
+        // 新しいモデルを読む前に古いモデルのメモリを解放しておく
+        *cached = None;
+        let loaded = load(path_model, gpu)?;
+        *cached = Some((path_model.to_path_buf(), gpu, loaded.clone()));
+        if let Ok(mut last_used) = self.last_used.lock() {
+            *last_used = Instant::now();
+        }
+        Ok(loaded)
+    }
+
+    // モデルのパスが変わったら、次に使うまで待たずにメモリを解放する
+    pub fn invalidate(&self) {
+        if let Ok(mut cached) = self.cached.lock() {
+            *cached = None;
+        }
+    }
+
+    pub fn lock_run(&self) -> Result<RunGuard<'_>, String> {
+        Ok(RunGuard {
+            _running: self.running.lock().map_err(|_| "Mutex is poisoned")?,
+            last_used: &self.last_used,
+        })
+    }
+
+    // 読み込んでいたモデルを解放し、解放したおおよそのバイト数（モデルファイルの大きさ）を返す。
+    // 文字起こし中はstate.fullがコンテキストを使っているので断る
+    pub fn unload(&self, app: &tauri::AppHandle, reason: &str) -> Result<u64, String> {
+        let _running = match self.running.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => {
+                return Err("文字起こし中はモデルを解放できません".to_string())
+            }
+            Err(TryLockError::Poisoned(_)) => return Err("Mutex is poisoned".to_string()),
+        };
+        let Some((path, _, loaded)) = self.cached.lock().map_err(|_| "Mutex is poisoned")?.take()
+        else {
+            return Ok(0);
+        };
+        drop(loaded);
+        let freed_bytes = fs::metadata(&path).map_or(0, |meta| meta.len());
+        let _ = app.emit_all(
+            "model-unloaded",
+            ModelUnloadedPayload {
+                reason: reason.to_string(),
+                path,
+                freed_bytes,
+            },
+        );
+        Ok(freed_bytes)
+    }
+
+    pub fn loaded_path(&self) -> Option<PathBuf> {
+        self.cached
+            .lock()
+            .ok()
+            .and_then(|cached| cached.as_ref().map(|(path, _, _)| path.clone()))
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.running.try_lock(), Err(TryLockError::WouldBlock))
+    }
+
+    fn is_idle_for(&self, timeout: Duration) -> bool {
+        self.last_used
+            .lock()
+            .is_ok_and(|last_used| last_used.elapsed() >= timeout)
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.cached.lock().is_ok_and(|cached| cached.is_some())
+    }
+}
+
+// GPUで読み込めなかったときはCPUで読み込み直す。
+// whisper-rs 0.10のWhisperContextParametersにはデバイスを選ぶ項目がないので、
+// gpu_deviceはキャッシュの区別にだけ使い、その後はwhisperの既定のデバイスになる
+pub fn load(path_model: &Path, gpu: GpuSettings) -> Result<LoadedModel, String> {
+    let path = path_model
+        .to_str()
+        .ok_or("言語モデルのパスが不正です".to_string())?;
+    let new_context = |use_gpu: bool| {
+        let mut params = WhisperContextParameters::default();
+        params.use_gpu(use_gpu);
+        // 前に読み込んだときのバックエンドの記録を消しておく
+        whisper_log::take_backend();
+        WhisperContext::new_with_params(path, params)
+    };
+    let (context, gpu_fallback) = match new_context(gpu.use_gpu) {
+        Ok(context) => (context, false),
+        Err(err) if gpu.use_gpu => {
+            tracing::warn!(
+                "failed to load the model on GPU, retrying on CPU: {:?}",
+                err
+            );
+            let context =
+                new_context(false).map_err(|_| "言語モデルの読み込みに失敗しました".to_string())?;
+            (context, true)
+        }
+        Err(_) => return Err("言語モデルの読み込みに失敗しました".to_string()),
+    };
+    let use_gpu = gpu.use_gpu && !gpu_fallback;
+    // ログにバックエンドが出なかったときは、組み込まれているGPUから判断する
+    let backend = whisper_log::take_backend().unwrap_or_else(|| {
+        system_info::parse(whisper_rs::print_system_info())
+            .backend(use_gpu)
+            .to_string()
+    });
+    Ok(LoadedModel {
+        context: Arc::new(context),
+        backend,
+        gpu_fallback,
+    })
+}
+
+// 設定した時間だけ文字起こしがなければモデルを解放する
+pub fn start_idle_unloader(app: tauri::AppHandle) {
+    thread::spawn(move || loop {
+        thread::sleep(IDLE_CHECK_INTERVAL);
+        let timeout = match STORE.lock() {
+            Ok(store) => store.get_model_idle_timeout(),
+            Err(_) => return,
+        };
+        if let Some(timeout) = timeout {
+            if MODEL_CACHE.is_loaded() && MODEL_CACHE.is_idle_for(timeout) {
+                let _ = MODEL_CACHE.unload(&app, "idle");
+            }
+        }
+    });
+}
+
+pub static MODEL_CACHE: Lazy<ModelCache> = Lazy::new(ModelCache::new);
@@ -0,0 +1,262 @@
+use crate::model_cache::MODEL_CACHE;
+use crate::models;
+use crate::store::STORE;
+use crate::whisper::{self, ModelSize};
+use futures_util::StreamExt;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use tauri::Manager;
+
+const MODEL_BASE_URL: &str = "https://huggingface.co/ggerganov/whisper.cpp/resolve/main";
+// 各ファイルのSHA-256（LFSのoid）とサイズはHugging Faceのファイル一覧から取る
+const MODEL_TREE_URL: &str = "https://huggingface.co/api/models/ggerganov/whisper.cpp/tree/main";
+
+const PROGRESS_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, serde::Deserialize)]
+struct HubFile {
+    path: String,
+    lfs: Option<HubLfs>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct HubLfs {
+    oid: String,
+    size: u64,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct ModelDownloadPayload {
+    status: String,
+    name: String,
+    bytes_downloaded: u64,
+    // 分からないときは0
+    total_bytes: u64,
+}
+
+#[derive(Debug)]
+pub enum DownloadError {
+    UnknownModel(String),
+    Http(String),
+    Io(io::Error),
+    Cancelled,
+    SizeMismatch { size: u64, expected: u64 },
+    HashMismatch(PathBuf),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::UnknownModel(name) => write!(f, "不明なモデルです: {}", name),
+            DownloadError::Http(err) => write!(f, "モデルのダウンロードに失敗しました: {}", err),
+            DownloadError::Io(err) => write!(f, "モデルを保存できませんでした: {}", err),
+            DownloadError::Cancelled => write!(f, "cancelled"),
+            DownloadError::SizeMismatch { size, expected } => write!(
+                f,
+                "ダウンロードしたモデルのサイズが一致しません（{} / {} バイト）",
+                size, expected
+            ),
+            DownloadError::HashMismatch(path) => write!(
+                f,
+                "ダウンロードしたモデルのハッシュが一致しません: {}",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(err: io::Error) -> Self {
+        DownloadError::Io(err)
+    }
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(err: reqwest::Error) -> Self {
+        DownloadError::Http(err.to_string())
+    }
+}
+
+// 英語専用のlargeはないので、largeは多言語のlarge-v3だけを取得できる
+pub fn model_name(model: ModelSize, english_only: bool) -> Result<String, DownloadError> {
+    let name = match model {
+        ModelSize::Tiny => "tiny",
+        ModelSize::Base => "base",
+        ModelSize::Small => "small",
+        ModelSize::Medium => "medium",
+        ModelSize::Large if english_only => {
+            return Err(DownloadError::UnknownModel("large.en".to_string()))
+        }
+        ModelSize::Large => "large-v3",
+    };
+    if english_only {
+        Ok(format!("{}.en", name))
+    } else {
+        Ok(name.to_string())
+    }
+}
+
+// 途中で止めた場合は.partを残しておき、次回はRangeリクエストで続きから取得する。
+// 取り消しは文字起こしと同じCANCEL_FLAGで行う。
+// ハッシュを確かめてからファイル名を戻し、Storeのモデルパスを書き換える
+pub async fn download_model(
+    model: ModelSize,
+    english_only: bool,
+    dest_dir: PathBuf,
+    app: &tauri::AppHandle,
+) -> Result<(), DownloadError> {
+    let name = model_name(model, english_only)?;
+    let cancel = whisper::CANCEL_FLAG.clone();
+    cancel.store(false, Ordering::SeqCst);
+    fs::create_dir_all(&dest_dir)?;
+    let file_name = format!("ggml-{}.bin", name);
+    let path = dest_dir.join(&file_name);
+    let path_part = dest_dir.join(format!("{}.part", file_name));
+    let client = reqwest::Client::new();
+    // 一覧を取得できなくてもダウンロードは続け、ハッシュは確かめない
+    let expected = match hub_file(&client, &file_name).await {
+        Ok(expected) => expected,
+        Err(err) => {
+            tracing::warn!("failed to fetch model list: {}", err);
+            None
+        }
+    };
+
+    let mut downloaded = fs::metadata(&path_part).map_or(0, |meta| meta.len());
+    let mut request = client.get(&format!("{}/{}", MODEL_BASE_URL, file_name));
+    if downloaded > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", downloaded));
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    // .partが最後まで取れているとRangeが範囲外になる。取り直さずにそのまま確かめる
+    let complete = status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE && downloaded > 0;
+    if !status.is_success() && !complete {
+        return Err(DownloadError::Http(status.to_string()));
+    }
+    // サーバーがRangeに応じなかったときは最初から取り直す
+    if status != reqwest::StatusCode::PARTIAL_CONTENT && !complete {
+        downloaded = 0;
+    }
+    // 一覧が取れなかったときは、標準のモデルなら既知の大きさと比べる
+    let known_size = expected
+        .as_ref()
+        .map(|lfs| lfs.size)
+        .or_else(|| models::standard_size(&file_name));
+    let total = if complete {
+        known_size
+    } else {
+        response
+            .content_length()
+            .map(|len| len + downloaded)
+            .or(known_size)
+    };
+
+    let emit = |status: &str, bytes_downloaded: u64| {
+        let _ = app.emit_all(
+            "model_download",
+            ModelDownloadPayload {
+                status: status.to_string(),
+                name: name.clone(),
+                bytes_downloaded,
+                total_bytes: total.unwrap_or_default(),
+            },
+        );
+    };
+    emit("progress", downloaded);
+    if !complete {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(downloaded > 0)
+            .write(true)
+            .truncate(downloaded == 0)
+            .open(&path_part)?;
+        let mut stream = response.bytes_stream();
+        let mut last_emit = downloaded;
+        while let Some(chunk) = stream.next().await {
+            if cancel.load(Ordering::SeqCst) {
+                emit("cancelled", downloaded);
+                return Err(DownloadError::Cancelled);
+            }
+            let chunk = chunk?;
+            file.write_all(&chunk)?;
+            downloaded += chunk.len() as u64;
+            if downloaded - last_emit >= PROGRESS_BYTES {
+                emit("progress", downloaded);
+                last_emit = downloaded;
+            }
+        }
+        file.flush()?;
+    }
+
+    let size = fs::metadata(&path_part)?.len();
+    if let Some(expected) = total.filter(|&total| total != size) {
+        // 範囲外と言われた.partが合わないなら、次も同じ応答になるので消して最初から取り直させる
+        if complete {
+            let _ = fs::remove_file(&path_part);
+        }
+        return Err(DownloadError::SizeMismatch { size, expected });
+    }
+    // 一覧が取れず照らし合わせられなかったときは、確かめたハッシュとして記録しない
+    let sha256 = match &expected {
+        Some(expected) => {
+            if models::sha256_file(&path_part)? != expected.oid {
+                // 壊れたファイルから続きを取らないよう消しておく
+                let _ = fs::remove_file(&path_part);
+                emit("hash_mismatch", downloaded);
+                return Err(DownloadError::HashMismatch(path));
+            }
+            emit("verified", downloaded);
+            Some(expected.oid.clone())
+        }
+        None => {
+            emit("unverified", downloaded);
+            None
+        }
+    };
+    fs::rename(&path_part, &path)?;
+    // 何とも比べられなかった大きさは、確かめた値として記録しない
+    if total.is_some() {
+        if let Err(err) =
+            models::record(&dest_dir, &file_name, models::ModelRecord { size, sha256 })
+        {
+            tracing::warn!("failed to write model manifest: {}", err);
+        }
+    }
+    emit("done", downloaded);
+    let changed = match STORE
+        .lock()
+        .map(|mut store| store.set_path_model(app, path))
+    {
+        Ok(Ok(changed)) => changed,
+        Ok(Err(err)) => {
+            tracing::warn!("failed to select the downloaded model: {}", err);
+            false
+        }
+        Err(_) => false,
+    };
+    if changed {
+        MODEL_CACHE.invalidate();
+    }
+    Ok(())
+}
+
+async fn hub_file(client: &reqwest::Client, file_name: &str) -> Result<Option<HubLfs>, String> {
+    let text = client
+        .get(MODEL_TREE_URL)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| err.to_string())?
+        .text()
+        .await
+        .map_err(|err| err.to_string())?;
+    let files: Vec<HubFile> = serde_json::from_str(&text).map_err(|err| err.to_string())?;
+    Ok(files
+        .into_iter()
+        .find(|file| file.path == file_name)
+        .and_then(|file| file.lfs))
+}
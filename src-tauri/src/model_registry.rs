@@ -0,0 +1,70 @@
+use crate::model_cache::MODEL_CACHE;
+use crate::models::{self, ModelInfo};
+use crate::whisper::ModelSize;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// モデルフォルダにある.binを大きさごとにまとめたもの
+pub struct ModelRegistry {
+    models: BTreeMap<ModelSize, Vec<PathBuf>>,
+}
+
+impl ModelRegistry {
+    fn new() -> ModelRegistry {
+        ModelRegistry {
+            models: BTreeMap::new(),
+        }
+    }
+
+    // ファイル名から大きさが分からないものは登録しない
+    pub fn scan(&mut self, dir: &Path) {
+        self.models.clear();
+        let paths = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && models::has_model_extension(path));
+        for path in paths {
+            if let Some(size) = models::parse_name(&path).size {
+                self.models.entry(size).or_default().push(path);
+            }
+        }
+        for paths in self.models.values_mut() {
+            paths.sort();
+        }
+    }
+
+    // 英語だけのモデルは言語が英語のときだけ選び、なければ見つかったものを使う
+    pub fn path_for(&self, size: ModelSize, english: bool) -> Option<&Path> {
+        let paths = self.models.get(&size)?;
+        paths
+            .iter()
+            .find(|path| models::parse_name(path).english_only == english)
+            .or_else(|| paths.first())
+            .map(PathBuf::as_path)
+    }
+
+    // 大きさの順に、見つかったファイルをすべて返す
+    pub fn list(&self, active: &Path) -> Vec<ModelInfo> {
+        let loaded = MODEL_CACHE.loaded_path();
+        self.models
+            .values()
+            .flatten()
+            .map(|path| models::model_info(path.clone(), active, loaded.as_deref()))
+            .collect()
+    }
+}
+
+pub fn scan(app: &tauri::AppHandle) {
+    if let Some(dir) = models::models_dir(app) {
+        if let Ok(mut registry) = MODEL_REGISTRY.lock() {
+            registry.scan(&dir);
+        }
+    }
+}
+
+pub static MODEL_REGISTRY: Lazy<Mutex<ModelRegistry>> =
+    Lazy::new(|| Mutex::new(ModelRegistry::new()));
@@ -0,0 +1,300 @@
+use crate::model_cache::MODEL_CACHE;
+use crate::store::STORE;
+use crate::whisper::ModelSize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use tauri::Manager;
+
+// ダウンロードしたモデルのサイズとSHA-256をモデルと同じフォルダに記録しておく
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+// 旧形式のggmlファイルは0x67676d6cをリトルエンディアンで書いている
+const GGML_MAGIC: [u8; 4] = *b"lmgg";
+const GGUF_MAGIC: [u8; 4] = *b"GGUF";
+
+// モデルとして扱うファイルの拡張子。設定、一覧、レジストリのどれもこれに合わせる
+pub const MODEL_EXTENSION: &str = "bin";
+
+// 配布されている標準のモデルの大きさ（Hugging Faceのggerganov/whisper.cppにあるもの）。
+// 配布元はSHA-1しか載せていないので、ハッシュはダウンロードしたときに記録したものだけを照合する
+const STANDARD_MODEL_SIZES: [(&str, u64); 12] = [
+    ("ggml-tiny.bin", 77_691_713),
+    ("ggml-tiny.en.bin", 77_704_715),
+    ("ggml-base.bin", 147_951_465),
+    ("ggml-base.en.bin", 147_964_211),
+    ("ggml-small.bin", 487_601_967),
+    ("ggml-small.en.bin", 487_614_201),
+    ("ggml-medium.bin", 1_533_763_059),
+    ("ggml-medium.en.bin", 1_533_774_781),
+    ("ggml-large-v1.bin", 3_094_623_691),
+    ("ggml-large-v2.bin", 3_094_623_691),
+    ("ggml-large-v3.bin", 3_095_033_483),
+    ("ggml-large-v3-turbo.bin", 1_624_555_275),
+];
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ModelRecord {
+    pub size: u64,
+    // 配布元のハッシュと照らし合わせられなかったときはNone。大きさだけを確かめる
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ModelInfo {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    model_size: Option<ModelSize>,
+    variant: Option<String>,
+    english_only: bool,
+    quantization: Option<String>,
+    tinydiarize: bool,
+    active: bool,
+    // 読み込み済みでキャッシュにある
+    is_loaded: bool,
+    invalid: bool,
+    error: Option<String>,
+}
+
+// ggml-small.en-q5_1.bin や ggml-small.en-tdrz.bin のようなファイル名から分かること
+pub struct ModelName {
+    pub size: Option<ModelSize>,
+    pub variant: Option<String>,
+    pub english_only: bool,
+    pub quantization: Option<String>,
+    pub tinydiarize: bool,
+}
+
+#[derive(Debug)]
+pub enum ModelError {
+    Missing(PathBuf),
+    Truncated {
+        path: PathBuf,
+        size: u64,
+        expected: u64,
+    },
+    NotGgml(PathBuf),
+    HashMismatch(PathBuf),
+    Io(PathBuf, io::Error),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Missing(path) => {
+                write!(f, "言語モデルが見つかりません: {}", path.display())
+            }
+            ModelError::Truncated {
+                path,
+                size,
+                expected,
+            } => write!(
+                f,
+                "言語モデルが途中で切れています（{} / {} バイト）: {}",
+                size,
+                expected,
+                path.display()
+            ),
+            ModelError::NotGgml(path) => {
+                write!(f, "ggml形式の言語モデルではありません: {}", path.display())
+            }
+            ModelError::HashMismatch(path) => {
+                write!(f, "言語モデルのハッシュが一致しません: {}", path.display())
+            }
+            ModelError::Io(path, err) => write!(
+                f,
+                "言語モデルを読み込めませんでした: {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+}
+
+// 設定でフォルダを指定していなければ、アプリのデータフォルダのmodelsを使う
+pub fn models_dir(app: &tauri::AppHandle) -> Option<PathBuf> {
+    let configured = STORE
+        .lock()
+        .ok()
+        .and_then(|store| store.get_models_dir().map(Path::to_path_buf));
+    configured.or_else(|| {
+        app.path_resolver()
+            .app_data_dir()
+            .map(|dir| dir.join("models"))
+    })
+}
+
+// モデルフォルダにあるモデルと、設定中のモデルの一覧を返す。
+// 壊れたファイルも整理できるようにinvalidを付けて一覧に残す
+pub fn list_models(app: &tauri::AppHandle) -> Result<Vec<ModelInfo>, String> {
+    let active = STORE
+        .lock()
+        .map_err(|_| "Mutex is poisoned")?
+        .get_path_model()
+        .to_path_buf();
+    let mut paths: Vec<PathBuf> = models_dir(app)
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && has_model_extension(path))
+        .collect();
+    if active.is_file() && !paths.contains(&active) {
+        paths.push(active.clone());
+    }
+    paths.sort();
+    let loaded = MODEL_CACHE.loaded_path();
+    Ok(paths
+        .into_iter()
+        .map(|path| model_info(path, &active, loaded.as_deref()))
+        .collect())
+}
+
+pub fn has_model_extension(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == MODEL_EXTENSION)
+}
+
+// ファイル名から大きさや種類を推測する。分からないものはNoneにする
+pub fn parse_name(path: &Path) -> ModelName {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let stem = stem.strip_prefix("ggml-").unwrap_or(&stem);
+    let mut parts = stem.split('-');
+    let base = parts.next().unwrap_or_default();
+    let suffixes: Vec<&str> = parts.collect();
+    let size = [
+        ("tiny", ModelSize::Tiny),
+        ("base", ModelSize::Base),
+        ("small", ModelSize::Small),
+        ("medium", ModelSize::Medium),
+        ("large", ModelSize::Large),
+    ]
+    .into_iter()
+    .find(|(prefix, _)| base.starts_with(prefix));
+    let variant = size.map(|(prefix, _)| match suffixes.first() {
+        Some(version) if prefix == "large" && version.starts_with('v') => {
+            format!("large-{}", version)
+        }
+        _ => prefix.to_string(),
+    });
+    ModelName {
+        size: size.map(|(_, size)| size),
+        variant,
+        english_only: base.ends_with(".en"),
+        quantization: suffixes
+            .iter()
+            .find(|suffix| suffix.starts_with('q') || **suffix == "f16")
+            .map(|suffix| suffix.to_string()),
+        tinydiarize: suffixes.contains(&"tdrz"),
+    }
+}
+
+// loadedは今キャッシュにあるモデルのパス。一覧を作る間に何度もキャッシュをロックしないよう渡してもらう
+pub fn model_info(path: PathBuf, active: &Path, loaded: Option<&Path>) -> ModelInfo {
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let parsed = parse_name(&path);
+    let error = validate(&path, false).err().map(|err| err.to_string());
+    ModelInfo {
+        size: fs::metadata(&path).map_or(0, |meta| meta.len()),
+        model_size: parsed.size,
+        english_only: parsed.english_only,
+        tinydiarize: parsed.tinydiarize,
+        active: path == active,
+        is_loaded: loaded == Some(path.as_path()),
+        invalid: error.is_some(),
+        name,
+        path,
+        variant: parsed.variant,
+        quantization: parsed.quantization,
+        error,
+    }
+}
+
+// 読み込む前にモデルファイルが使えそうか確かめる。サイズは標準のモデルとこのアプリでダウンロードしたモデルを、
+// ハッシュはダウンロードしたときに確かめられたモデルだけを照合できる
+pub fn validate(path: &Path, verify_hash: bool) -> Result<(), ModelError> {
+    let io_err = |err| ModelError::Io(path.to_path_buf(), err);
+    let size = match fs::metadata(path) {
+        Ok(meta) if meta.is_file() => meta.len(),
+        Ok(_) => return Err(ModelError::Missing(path.to_path_buf())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(ModelError::Missing(path.to_path_buf()))
+        }
+        Err(err) => return Err(io_err(err)),
+    };
+    let mut magic = [0; 4];
+    match fs::File::open(path).and_then(|mut file| file.read_exact(&mut magic)) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(ModelError::NotGgml(path.to_path_buf()))
+        }
+        Err(err) => return Err(io_err(err)),
+    }
+    if magic != GGML_MAGIC && magic != GGUF_MAGIC {
+        return Err(ModelError::NotGgml(path.to_path_buf()));
+    }
+    let Some(record) = known_record(path) else {
+        return Ok(());
+    };
+    if size < record.size {
+        return Err(ModelError::Truncated {
+            path: path.to_path_buf(),
+            size,
+            expected: record.size,
+        });
+    }
+    let Some(sha256) = record.sha256.filter(|_| verify_hash) else {
+        return Ok(());
+    };
+    if sha256_file(path).map_err(io_err)? != sha256 {
+        return Err(ModelError::HashMismatch(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+fn read_manifest(dir: &Path) -> BTreeMap<String, ModelRecord> {
+    fs::read(dir.join(MANIFEST_FILE_NAME))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+pub fn record(dir: &Path, file_name: &str, record: ModelRecord) -> io::Result<()> {
+    let mut manifest = read_manifest(dir);
+    manifest.insert(file_name.to_string(), record);
+    fs::write(
+        dir.join(MANIFEST_FILE_NAME),
+        serde_json::to_vec_pretty(&manifest)?,
+    )
+}
+
+// ダウンロードしたときの記録がなければ、標準のモデルの大きさだけを使う
+fn known_record(path: &Path) -> Option<ModelRecord> {
+    let file_name = path.file_name()?.to_str()?;
+    path.parent()
+        .and_then(|dir| read_manifest(dir).remove(file_name))
+        .or_else(|| standard_size(file_name).map(|size| ModelRecord { size, sha256: None }))
+}
+
+pub fn standard_size(file_name: &str) -> Option<u64> {
+    STANDARD_MODEL_SIZES
+        .iter()
+        .find(|(name, _)| *name == file_name)
+        .map(|&(_, size)| size)
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,179 @@
+use crate::batch;
+use crate::error::WhisperError;
+use crate::store::STORE;
+use crate::whisper;
+use once_cell::sync::Lazy;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use tauri::Manager;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct QueueItem {
+    id: u64,
+    path: String,
+    status: QueueStatus,
+    message: Option<String>,
+}
+
+pub struct Queue {
+    items: Vec<QueueItem>,
+    next_id: u64,
+    worker_running: bool,
+}
+
+impl Queue {
+    fn new() -> Queue {
+        Queue {
+            items: Vec::new(),
+            next_id: 1,
+            worker_running: false,
+        }
+    }
+
+    pub fn get_items(&self) -> Vec<QueueItem> {
+        self.items.clone()
+    }
+
+    // 実行中の項目は取り消してから消す
+    pub fn remove(&mut self, id: u64) -> Result<(), String> {
+        let index = self
+            .items
+            .iter()
+            .position(|item| item.id == id)
+            .ok_or_else(|| format!("キューに見つかりません: {}", id))?;
+        if self.items[index].status == QueueStatus::Running {
+            return Err("実行中の項目は削除できません。先に取り消してください".to_string());
+        }
+        self.items.remove(index);
+        Ok(())
+    }
+
+    fn next_pending(&mut self) -> Option<QueueItem> {
+        let item = self
+            .items
+            .iter_mut()
+            .find(|item| item.status == QueueStatus::Pending)?;
+        item.status = QueueStatus::Running;
+        Some(item.clone())
+    }
+
+    fn finish(
+        &mut self,
+        id: u64,
+        status: QueueStatus,
+        message: Option<String>,
+    ) -> Option<QueueItem> {
+        let item = self.items.iter_mut().find(|item| item.id == id)?;
+        item.status = status;
+        item.message = message;
+        Some(item.clone())
+    }
+}
+
+pub fn enqueue_files(paths: Vec<String>, app: tauri::AppHandle) -> Result<Vec<u64>, String> {
+    let mut queue = QUEUE.lock().map_err(|_| "Mutex is poisoned")?;
+    let mut ids = Vec::new();
+    for path in paths {
+        let item = QueueItem {
+            id: queue.next_id,
+            path,
+            status: QueueStatus::Pending,
+            message: None,
+        };
+        queue.next_id += 1;
+        ids.push(item.id);
+        emit_queue(&app, &item);
+        queue.items.push(item);
+    }
+    if !queue.worker_running {
+        queue.worker_running = true;
+        tauri::async_runtime::spawn(run_worker(app));
+    }
+    Ok(ids)
+}
+
+// 実行中の項目だけを取り消し、残りはそのまま続ける
+pub fn cancel_current() {
+    whisper::CANCEL_FLAG.store(true, Ordering::SeqCst);
+}
+
+// 待ちの項目をすべて消し、実行中の項目も取り消す
+pub fn clear(app: &tauri::AppHandle) -> Result<(), String> {
+    let mut queue = QUEUE.lock().map_err(|_| "Mutex is poisoned")?;
+    let cancelled: Vec<QueueItem> = queue
+        .items
+        .iter()
+        .filter(|item| item.status == QueueStatus::Pending)
+        .cloned()
+        .collect();
+    queue
+        .items
+        .retain(|item| item.status != QueueStatus::Pending);
+    let running = queue
+        .items
+        .iter()
+        .any(|item| item.status == QueueStatus::Running);
+    drop(queue);
+    for mut item in cancelled {
+        item.status = QueueStatus::Cancelled;
+        emit_queue(app, &item);
+    }
+    if running {
+        cancel_current();
+    }
+    Ok(())
+}
+
+// 1件ずつ文字起こしして、ファイルごとに字幕を書き出す。失敗しても次の項目へ進む。
+// 項目ごとに結果を消すので、開いていた文字起こし結果は一括処理と同じく終わってから戻す
+async fn run_worker(app: tauri::AppHandle) {
+    let before = STORE
+        .lock()
+        .map(|mut store| (store.get_path_wav().to_path_buf(), store.take_transcript()))
+        .ok();
+    loop {
+        let item = match QUEUE.lock() {
+            Ok(mut queue) => match queue.next_pending() {
+                Some(item) => item,
+                None => {
+                    queue.worker_running = false;
+                    break;
+                }
+            },
+            Err(_) => return,
+        };
+        emit_queue(&app, &item);
+        let (status, message) = match batch::transcribe_one(&item.path, &app).await {
+            Ok(()) => (QueueStatus::Done, None),
+            Err(WhisperError::Cancelled) => (QueueStatus::Cancelled, None),
+            Err(err) => (QueueStatus::Failed, Some(err.to_string())),
+        };
+        let finished = QUEUE
+            .lock()
+            .ok()
+            .and_then(|mut queue| queue.finish(item.id, status, message));
+        if let Some(item) = finished {
+            emit_queue(&app, &item);
+        }
+    }
+    if let (Some((path_wav, transcript)), Ok(mut store)) = (before, STORE.lock()) {
+        store.set_path_wav(&app, path_wav);
+        store.restore_transcript(&app, transcript);
+    }
+}
+
+fn emit_queue(app: &tauri::AppHandle, item: &QueueItem) {
+    let _ = app.emit_all("queue", item.clone());
+}
+
+pub static QUEUE: Lazy<Mutex<Queue>> = Lazy::new(|| Mutex::new(Queue::new()));
@@ -1,7 +1,21 @@
+use crate::audio;
+use crate::export::{ts, Cue};
+use crate::messages::{self, UiLang};
+use crate::model_cache::GpuSettings;
+use crate::models::{self, ModelError};
 use once_cell::sync::Lazy;
+use regex::{NoExpand, Regex, RegexBuilder};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 use tauri::Manager;
+use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Debug)]
 pub struct Store {
@@ -9,8 +23,59 @@ pub struct Store {
     wav_load_status: LoadStatus,
     model_load_status: LoadStatus,
     data: Vec<Data>,
+    dirty: bool,
+    undo_stack: VecDeque<Edit>,
+    redo_stack: Vec<Edit>,
+    // 自動保存がまだの変更をした時刻
+    last_change: Option<Instant>,
 }
 
+const MAX_UNDO: usize = 200;
+
+// dataのindexからremovedを取り除いてinsertedを入れた、という1回分の変更。
+// 取り消すときは逆向きに入れ替える
+#[derive(Debug, Clone)]
+struct Edit {
+    op: &'static str,
+    index: usize,
+    removed: Vec<Data>,
+    inserted: Vec<Data>,
+}
+
+// 一括処理の間だけ退避しておく、文字起こし結果と編集履歴
+#[derive(Debug)]
+pub struct SavedTranscript {
+    data: Vec<Data>,
+    undo_stack: VecDeque<Edit>,
+    redo_stack: Vec<Edit>,
+}
+
+// 後から増えた項目は既定値で読み、知らない項目は無視する
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Session {
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    config: Config,
+    #[serde(default)]
+    data: Vec<Data>,
+}
+
+const SESSION_VERSION: u32 = 1;
+
+// プロジェクトファイルはセッションと同じ形式で、好きな場所に保存できる
+pub const PROJECT_EXTENSION: &str = "pothook";
+
+// whisperのテキストコンテキストの上限（n_text_ctxの半分）
+const MAX_CONTEXT_TOKENS: i32 = 1500;
+const MAX_AUDIO_CTX: u32 = 1500;
+// これより小さいモデルファイルは、ダウンロードに失敗した空のファイルなどとみなす
+const MIN_MODEL_BYTES: u64 = 1024 * 1024;
+
+// 最後に変更してからこの時間がたったら自動保存する
+const AUTOSAVE_DEBOUNCE: Duration = Duration::from_secs(2);
+const AUTOSAVE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum Status {
     NotReady,
@@ -26,40 +91,531 @@ pub enum LoadStatus {
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct Config {
     status: Status,
     path_wav: PathBuf,
     path_model: PathBuf,
+    models_dir: Option<PathBuf>,
     display_clock: bool,
     lang: String,
     translate: bool,
-    sec_start: i32,
-    sec_end: i32,
+    // 小数で秒未満も指定できる。以前の整数の値もそのまま読める
+    sec_start: f64,
+    sec_end: f64,
+    progress_interval: i32,
+    channel: Option<usize>,
+    audio_track: usize,
+    line_length: usize,
+    sampling_strategy: SamplingStrategyConfig,
+    n_threads: Option<u32>,
+    input_device_name: Option<String>,
+    initial_prompt: Option<String>,
+    temperature: f32,
+    temperature_inc: f32,
+    entropy_thold: f32,
+    logprob_thold: f32,
+    no_speech_thold: f32,
+    word_timestamps: bool,
+    max_len: i32,
+    split_on_word: bool,
+    max_context_tokens: i32,
+    audio_ctx: Option<u32>,
+    max_segment_chars: Option<usize>,
+    model_idle_timeout_min: Option<u32>,
+    detected_lang: Option<String>,
+    lang_detect_min_prob: f32,
+    fallback_lang: Option<String>,
+    suppress_tokens: Vec<i32>,
+    suppress_blank: bool,
+    repeat_filter: RepeatFilter,
+    repeat_window: usize,
+    repeat_threshold: usize,
+    speaker_turn_mark: SpeakerTurnMark,
+    vad: bool,
+    vad_min_silence_ms: usize,
+    ui_lang: UiLang,
+    gap_merge_ms: Option<u64>,
+    metrics: bool,
+    use_gpu: bool,
+    gpu_device: i32,
 }
 
-#[derive(Debug)]
-struct Data {
+// 書き出すときに話者が替わったところへ入れる印
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeakerTurnMark {
+    None,
+    // SRTとVTTはキューの中に空行を入れられないので">>"にする
+    Blank,
+    Chevron,
+}
+
+impl FromStr for SpeakerTurnMark {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" | "" => Ok(SpeakerTurnMark::None),
+            "blank" => Ok(SpeakerTurnMark::Blank),
+            "chevron" => Ok(SpeakerTurnMark::Chevron),
+            _ => Err(format!("話者の区切りの指定が不正です: {}", s)),
+        }
+    }
+}
+
+// 無音や音楽だけの区間でwhisperが同じ文を繰り返し出すときの扱い
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatFilter {
+    Off,
+    Mark,
+    Drop,
+}
+
+impl FromStr for RepeatFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(RepeatFilter::Off),
+            "mark" => Ok(RepeatFilter::Mark),
+            "drop" => Ok(RepeatFilter::Drop),
+            _ => Err(format!("繰り返しの扱いの指定が不正です: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct FallbackSettings {
+    pub temperature: f32,
+    pub temperature_inc: f32,
+    pub entropy_thold: f32,
+    pub logprob_thold: f32,
+    pub no_speech_thold: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SamplingStrategyConfig {
+    Greedy {
+        #[serde(rename = "bestOf")]
+        best_of: u32,
+    },
+    BeamSearch {
+        #[serde(rename = "beamSize")]
+        beam_size: u32,
+        patience: f32,
+    },
+}
+
+impl SamplingStrategyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            SamplingStrategyConfig::Greedy { best_of: 0 } => {
+                Err("best_ofは1以上にしてください".to_string())
+            }
+            SamplingStrategyConfig::BeamSearch { beam_size: 0, .. } => {
+                Err("beam_sizeは1以上にしてください".to_string())
+            }
+            SamplingStrategyConfig::BeamSearch { patience, .. }
+                if patience < 0. || patience.is_nan() =>
+            {
+                Err(format!("patienceは0以上にしてください: {}", patience))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Data {
     ms_start: i64,
     ms_end: i64,
     subtitle: String,
+    words: Vec<Word>,
+    #[serde(default)]
+    confidence: f32,
+    #[serde(default)]
+    speaker_turn: bool,
+    // 手で直したセグメント
+    #[serde(default)]
+    edited: bool,
+    // 繰り返しや無音から出たと思われるセグメント
+    #[serde(default)]
+    suspect: bool,
+}
+
+impl Data {
+    pub fn new(
+        ms_start: i64,
+        ms_end: i64,
+        subtitle: String,
+        words: Vec<Word>,
+        confidence: f32,
+        speaker_turn: bool,
+        suspect: bool,
+    ) -> Data {
+        Data {
+            ms_start,
+            ms_end,
+            subtitle,
+            words,
+            confidence,
+            speaker_turn,
+            edited: false,
+            suspect,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SpeakerSegment {
+    pub index: usize,
+    pub ms_start: i64,
+    pub ms_end: i64,
+    pub subtitle: String,
+    pub speaker: String,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct StoreChangedPayload {
+    op: String,
+    index: usize,
+    removed: usize,
+    inserted: usize,
+    count: usize,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SegmentData {
+    pub index: usize,
+    pub t0_ms: i64,
+    pub t1_ms: i64,
+    pub text: String,
+    pub confidence: f32,
+    pub edited: bool,
+    pub suspect: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SearchMatch {
+    pub index: usize,
+    pub ms_start: i64,
+    pub ms_end: i64,
+    // 字幕の中で一致したバイト位置（開始, 終了）
+    pub ranges: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Statistics {
+    pub segment_count: usize,
+    pub total_duration_ms: u64,
+    pub word_count: usize,
+    pub avg_confidence: f32,
+    // 音声全体のうち、どれかのセグメントに含まれている時間の割合（%）
+    pub coverage_pct: f32,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ReplaceCount {
+    pub index: usize,
+    pub count: usize,
+}
+
+// 正規表現をコンパイルしたときの大きさの上限。これを超えるパターンは受け付けない
+const SEARCH_REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LowConfidenceSegment {
+    pub index: usize,
+    pub ms_start: i64,
+    pub ms_end: i64,
+    pub subtitle: String,
+    pub confidence: f32,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    ModelMissing(PathBuf),
+    ModelExtension(PathBuf),
+    WavMissing(PathBuf),
+    UnknownLang(String),
+    NegativeTime { name: &'static str, value: f64 },
+    InvalidModelPath(String),
+    InvalidModel(ModelError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ModelMissing(path) => {
+                write!(f, "言語モデルが見つかりません: {}", path.display())
+            }
+            ConfigError::ModelExtension(path) => write!(
+                f,
+                "言語モデルは.binファイルを指定してください: {}",
+                path.display()
+            ),
+            ConfigError::WavMissing(path) => {
+                write!(f, "音声ファイルが見つかりません: {}", path.display())
+            }
+            ConfigError::UnknownLang(lang) => write!(f, "対応していない言語です: {}", lang),
+            ConfigError::NegativeTime { name, value } => {
+                write!(f, "{}には0以上の値を指定してください: {}", name, value)
+            }
+            ConfigError::InvalidModelPath(message) => write!(f, "{}", message),
+            ConfigError::InvalidModel(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    NewerVersion(u32),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "セッションファイルを開けませんでした: {}", err),
+            LoadError::Parse(err) => write!(f, "セッションファイルを読み込めませんでした: {}", err),
+            LoadError::NewerVersion(version) => write!(
+                f,
+                "新しいバージョンで保存されたファイルです（形式 {}）。アプリを更新してください",
+                version
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Word {
+    pub word: String,
+    pub t0: i64,
+    pub t1: i64,
+    pub probability: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            status: Status::NotReady,
+            display_clock: true,
+            path_wav: PathBuf::new(),
+            path_model: PathBuf::new(),
+            models_dir: None,
+            lang: "ja".to_string(),
+            translate: false,
+            sec_start: 0.,
+            sec_end: 0.,
+            progress_interval: 1,
+            channel: None,
+            audio_track: 0,
+            line_length: 42,
+            sampling_strategy: SamplingStrategyConfig::Greedy { best_of: 1 },
+            n_threads: None,
+            input_device_name: None,
+            initial_prompt: None,
+            temperature: 0.0,
+            temperature_inc: 0.2,
+            entropy_thold: 2.4,
+            logprob_thold: -1.0,
+            no_speech_thold: 0.6,
+            word_timestamps: false,
+            max_len: 0,
+            split_on_word: true,
+            max_context_tokens: -1,
+            audio_ctx: None,
+            max_segment_chars: None,
+            model_idle_timeout_min: None,
+            detected_lang: None,
+            lang_detect_min_prob: 0.5,
+            fallback_lang: None,
+            suppress_tokens: vec![-1],
+            suppress_blank: true,
+            repeat_filter: RepeatFilter::Off,
+            repeat_window: 5,
+            repeat_threshold: 2,
+            speaker_turn_mark: SpeakerTurnMark::None,
+            vad: false,
+            vad_min_silence_ms: 2000,
+            ui_lang: UiLang::Ja,
+            gap_merge_ms: None,
+            metrics: false,
+            use_gpu: true,
+            gpu_device: 0,
+        }
+    }
+}
+
+// 設定する前に、モデルとして読めそうなファイルか確かめる
+fn check_model_path(path: &Path) -> Result<(), String> {
+    let meta = fs::metadata(path)
+        .map_err(|_| format!("言語モデルが見つかりません: {}", path.display()))?;
+    if !meta.is_file() {
+        return Err(format!(
+            "言語モデルにはファイルを指定してください: {}",
+            path.display()
+        ));
+    }
+    if !models::has_model_extension(path) {
+        return Err(format!(
+            "言語モデルは.{}ファイルを指定してください: {}",
+            models::MODEL_EXTENSION,
+            path.display()
+        ));
+    }
+    if meta.len() <= MIN_MODEL_BYTES {
+        return Err(format!(
+            "言語モデルのファイルが小さすぎます（{}バイト）: {}",
+            meta.len(),
+            path.display()
+        ));
+    }
+    fs::File::open(path)
+        .map_err(|err| format!("言語モデルを読み込めません: {}: {}", path.display(), err))?;
+    Ok(())
+}
+
+// secondをfirstの後ろにつなげる。確からしさは長さで重みをつけて平均する
+fn merge_data(first: &mut Data, second: Data) {
+    let first_ms = (first.ms_end - first.ms_start).max(1) as f32;
+    let second_ms = (second.ms_end - second.ms_start).max(1) as f32;
+    first.confidence =
+        (first.confidence * first_ms + second.confidence * second_ms) / (first_ms + second_ms);
+    first.ms_start = first.ms_start.min(second.ms_start);
+    first.ms_end = first.ms_end.max(second.ms_end);
+    // whisperは字幕の先頭に空白を付けるので、それを取ってから空白1つでつなげる
+    first.subtitle = format!("{} {}", first.subtitle.trim(), second.subtitle.trim());
+    first.words.extend(second.words);
+    first.speaker_turn = second.speaker_turn;
+    first.edited |= second.edited;
+}
+
+// 前のセグメントの終わりから次の始まりまでがmax_gap_msより短ければつなげる。話者が替わるところはつなげない
+fn merge_gaps(data: Vec<Data>, max_gap_ms: u64) -> Vec<Data> {
+    let mut merged: Vec<Data> = Vec::with_capacity(data.len());
+    for d in data {
+        match merged.last_mut() {
+            Some(last) if !last.speaker_turn && d.ms_start - last.ms_end < max_gap_ms as i64 => {
+                merge_data(last, d)
+            }
+            _ => merged.push(d),
+        }
+    }
+    merged
 }
 
 impl Store {
     fn new() -> Store {
         Store {
-            config: Config {
-                status: Status::NotReady,
-                display_clock: true,
-                path_wav: PathBuf::new(),
-                path_model: PathBuf::new(),
-                lang: "ja".to_string(),
-                translate: false,
-                sec_start: 0,
-                sec_end: 0,
-            },
+            config: Config::default(),
             wav_load_status: LoadStatus::StandBy,
             model_load_status: LoadStatus::StandBy,
             data: Vec::new(),
+            dirty: false,
+            undo_stack: VecDeque::new(),
+            redo_stack: Vec::new(),
+            last_change: None,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Store, LoadError> {
+        let session: Session =
+            serde_json::from_str(&fs::read_to_string(path).map_err(LoadError::Io)?)
+                .map_err(LoadError::Parse)?;
+        if session.version > SESSION_VERSION {
+            return Err(LoadError::NewerVersion(session.version));
+        }
+        Ok(Store {
+            config: session.config,
+            data: session.data,
+            ..Store::new()
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let session = Session {
+            version: SESSION_VERSION,
+            config: self.config.clone(),
+            data: self.data.clone(),
+        };
+        // 書き込み中に落ちても前のファイルが残るよう、一時ファイルに書いてから置き換える
+        let mut path_tmp = path.as_os_str().to_owned();
+        path_tmp.push(".tmp");
+        fs::write(&path_tmp, serde_json::to_string(&session)?)?;
+        fs::rename(&path_tmp, path)
+    }
+
+    fn autosave_due(&self) -> bool {
+        self.last_change
+            .is_some_and(|last_change| last_change.elapsed() >= AUTOSAVE_DEBOUNCE)
+    }
+
+    // 復元したStoreに差し替えて、画面に設定と結果を送り直す
+    pub fn restore(&mut self, app: &tauri::AppHandle, restored: Store) {
+        *self = restored;
+        messages::set_ui_lang(self.config.ui_lang);
+        self.emit_config(app);
+        self.emit_data(app);
+    }
+
+    // 前回保存してから変更があったときだけ書き出す
+    pub fn save_if_dirty(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        if self.dirty {
+            self.save(path)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    // プロジェクトを開いたあとで音声ファイルが移動していると、結果の編集しかできない
+    pub fn is_audio_missing(&self) -> bool {
+        let path_wav = &self.config.path_wav;
+        !path_wav.as_os_str().is_empty() && !path_wav.is_file()
+    }
+
+    pub fn last_subtitle(&self) -> Option<String> {
+        self.data.last().map(|d| d.subtitle.clone())
+    }
+
+    pub fn num_data(&self) -> usize {
+        self.data.len()
+    }
+
+    // 文字起こしを始める前に設定をまとめて確かめる。録音から文字起こしするときは音声ファイルが空でもよい
+    pub fn validate_config(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let path_model = &self.config.path_model;
+        if !path_model.is_file() {
+            errors.push(ConfigError::ModelMissing(path_model.clone()));
+        } else if !models::has_model_extension(path_model) {
+            errors.push(ConfigError::ModelExtension(path_model.clone()));
+        }
+        if self.is_audio_missing() {
+            errors.push(ConfigError::WavMissing(self.config.path_wav.clone()));
+        }
+        if !self.is_auto_lang() && whisper_rs::get_lang_id(&self.config.lang).is_none() {
+            errors.push(ConfigError::UnknownLang(self.config.lang.clone()));
+        }
+        for (name, value) in [
+            ("sec_start", self.config.sec_start),
+            ("sec_end", self.config.sec_end),
+        ] {
+            if value < 0. {
+                errors.push(ConfigError::NegativeTime { name, value });
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
         }
     }
 
@@ -86,9 +642,31 @@ impl Store {
         &(self.config.path_model)
     }
 
-    pub fn set_path_model(&mut self, app: &tauri::AppHandle, path_model: PathBuf) {
+    // 空ならモデルの指定を外す。パスが変わったときはtrueを返すので、呼び出し側はSTOREを放してから
+    // MODEL_CACHE.invalidate()を呼ぶ（モデルを読み込んでいる間はキャッシュのロックが塞がっている）
+    pub fn set_path_model(
+        &mut self,
+        app: &tauri::AppHandle,
+        path_model: PathBuf,
+    ) -> Result<bool, ConfigError> {
+        if !path_model.as_os_str().is_empty() {
+            check_model_path(&path_model).map_err(ConfigError::InvalidModelPath)?;
+            models::validate(&path_model, false).map_err(ConfigError::InvalidModel)?;
+        }
+        let changed = self.config.path_model != path_model;
         self.config.path_model = path_model;
         self.emit_config(app);
+        Ok(changed)
+    }
+
+    // 指定がなければアプリのデータフォルダのmodelsを使う
+    pub fn get_models_dir(&self) -> Option<&Path> {
+        self.config.models_dir.as_deref()
+    }
+
+    pub fn set_models_dir(&mut self, app: &tauri::AppHandle, models_dir: Option<PathBuf>) {
+        self.config.models_dir = models_dir;
+        self.emit_config(app);
     }
 
     pub fn set_display_clock(&mut self, app: &tauri::AppHandle, display_clock: bool) {
@@ -105,6 +683,52 @@ impl Store {
         self.emit_config(app);
     }
 
+    pub fn set_detected_lang(&mut self, app: &tauri::AppHandle, detected_lang: Option<String>) {
+        self.config.detected_lang = detected_lang;
+        self.emit_config(app);
+    }
+
+    // "auto"か空文字なら言語を自動判定する
+    pub fn is_auto_lang(&self) -> bool {
+        matches!(self.config.lang.as_str(), "auto" | "")
+    }
+
+    pub fn get_lang_detect_min_prob(&self) -> f32 {
+        self.config.lang_detect_min_prob
+    }
+
+    pub fn set_lang_detect_min_prob(
+        &mut self,
+        app: &tauri::AppHandle,
+        lang_detect_min_prob: f32,
+    ) -> Result<(), String> {
+        check_range("lang_detect_min_prob", lang_detect_min_prob, 0.0..=1.0)?;
+        self.config.lang_detect_min_prob = lang_detect_min_prob;
+        self.emit_config(app);
+        Ok(())
+    }
+
+    // 自動判定の確率がしきい値に届かなかったときに使う言語
+    pub fn get_fallback_lang(&self) -> Option<&str> {
+        self.config
+            .fallback_lang
+            .as_deref()
+            .filter(|lang| !matches!(*lang, "auto" | ""))
+    }
+
+    pub fn set_fallback_lang(&mut self, app: &tauri::AppHandle, fallback_lang: Option<String>) {
+        self.config.fallback_lang = fallback_lang;
+        self.emit_config(app);
+    }
+
+    // 自動判定のときは判定結果の言語を返す
+    pub fn get_effective_lang(&self) -> &str {
+        match &self.config.detected_lang {
+            Some(detected_lang) if self.is_auto_lang() => detected_lang,
+            _ => &self.config.lang,
+        }
+    }
+
     pub fn get_translate(&self) -> bool {
         self.config.translate
     }
@@ -114,54 +738,1124 @@ impl Store {
         self.emit_config(app);
     }
 
-    pub fn set_sec_start(&mut self, app: &tauri::AppHandle, sec_start: i32) {
+    pub fn set_sec_start(&mut self, app: &tauri::AppHandle, sec_start: f64) {
         self.config.sec_start = sec_start;
         self.emit_config(app);
     }
 
-    pub fn set_sec_end(&mut self, app: &tauri::AppHandle, sec_end: i32) {
+    pub fn set_sec_end(&mut self, app: &tauri::AppHandle, sec_end: f64) {
         self.config.sec_end = sec_end;
         self.emit_config(app);
     }
 
+    // 動画編集ソフトの秒数（1.5秒など）をそのまま受け取り、ミリ秒に丸めて使う
+    pub fn set_offset_seconds(
+        &mut self,
+        app: &tauri::AppHandle,
+        offset_s: f64,
+    ) -> Result<(), String> {
+        let offset_ms = seconds_to_ms("offset_s", offset_s)?;
+        // 長さを変えずに開始位置だけを動かす
+        let duration_ms = self.get_ms_duration();
+        self.config.sec_start = offset_ms as f64 / 1000.;
+        self.config.sec_end = if duration_ms > 0 {
+            (offset_ms + duration_ms) as f64 / 1000.
+        } else {
+            0.
+        };
+        self.emit_config(app);
+        Ok(())
+    }
+
+    // 0なら最後まで
+    pub fn set_duration_seconds(
+        &mut self,
+        app: &tauri::AppHandle,
+        duration_s: f64,
+    ) -> Result<(), String> {
+        let duration_ms = seconds_to_ms("duration_s", duration_s)?;
+        self.config.sec_end = if duration_ms > 0 {
+            (self.get_ms_offset() + duration_ms) as f64 / 1000.
+        } else {
+            0.
+        };
+        self.emit_config(app);
+        Ok(())
+    }
+
+    // 進捗を通知する間隔（%）
+    pub fn get_progress_interval(&self) -> i32 {
+        self.config.progress_interval.max(1)
+    }
+
+    pub fn set_progress_interval(&mut self, app: &tauri::AppHandle, progress_interval: i32) {
+        self.config.progress_interval = progress_interval;
+        self.emit_config(app);
+    }
+
+    pub fn get_channel(&self) -> Option<usize> {
+        self.config.channel
+    }
+
+    pub fn set_channel(&mut self, app: &tauri::AppHandle, channel: Option<usize>) {
+        self.config.channel = channel;
+        self.emit_config(app);
+    }
+
+    pub fn get_audio_track(&self) -> usize {
+        self.config.audio_track
+    }
+
+    pub fn set_audio_track(&mut self, app: &tauri::AppHandle, audio_track: usize) {
+        self.config.audio_track = audio_track;
+        self.emit_config(app);
+    }
+
+    pub fn get_line_length(&self) -> usize {
+        self.config.line_length
+    }
+
+    pub fn set_line_length(&mut self, app: &tauri::AppHandle, line_length: usize) {
+        self.config.line_length = line_length;
+        self.emit_config(app);
+    }
+
+    pub fn get_speaker_turn_mark(&self) -> SpeakerTurnMark {
+        self.config.speaker_turn_mark
+    }
+
+    pub fn set_speaker_turn_mark(
+        &mut self,
+        app: &tauri::AppHandle,
+        speaker_turn_mark: SpeakerTurnMark,
+    ) {
+        self.config.speaker_turn_mark = speaker_turn_mark;
+        self.emit_config(app);
+    }
+
+    pub fn get_ui_lang(&self) -> UiLang {
+        self.config.ui_lang
+    }
+
+    pub fn set_ui_lang(&mut self, app: &tauri::AppHandle, ui_lang: UiLang) {
+        self.config.ui_lang = ui_lang;
+        messages::set_ui_lang(ui_lang);
+        self.emit_config(app);
+    }
+
+    pub fn get_sampling_strategy(&self) -> SamplingStrategyConfig {
+        self.config.sampling_strategy
+    }
+
+    pub fn set_sampling_strategy(
+        &mut self,
+        app: &tauri::AppHandle,
+        sampling_strategy: SamplingStrategyConfig,
+    ) {
+        self.config.sampling_strategy = sampling_strategy;
+        self.emit_config(app);
+    }
+
+    // 未設定なら物理コア数から決める。ハイパースレッディングの論理コアまで使うと
+    // かえって遅くなることが多いので、物理コア数を超える値は丸める
+    pub fn get_n_threads(&self) -> i32 {
+        let physical = num_cpus::get_physical().max(1) as u32;
+        match self.config.n_threads {
+            Some(n_threads) => n_threads.clamp(1, physical) as i32,
+            None => physical.min(4) as i32,
+        }
+    }
+
+    pub fn set_n_threads(&mut self, app: &tauri::AppHandle, n_threads: Option<u32>) {
+        self.config.n_threads = n_threads;
+        self.emit_config(app);
+    }
+
+    pub fn get_input_device_name(&self) -> Option<&str> {
+        self.config.input_device_name.as_deref()
+    }
+
+    pub fn set_input_device_name(&mut self, app: &tauri::AppHandle, name: Option<String>) {
+        self.config.input_device_name = name;
+        self.emit_config(app);
+    }
+
+    pub fn get_initial_prompt(&self) -> Option<&str> {
+        self.config
+            .initial_prompt
+            .as_deref()
+            .filter(|prompt| !prompt.trim().is_empty())
+    }
+
+    pub fn set_initial_prompt(&mut self, app: &tauri::AppHandle, initial_prompt: Option<String>) {
+        // NUL文字が含まれるとCStringに変換できないので取り除いておく
+        self.config.initial_prompt = initial_prompt.map(|prompt| prompt.replace('\0', ""));
+        self.emit_config(app);
+    }
+
+    pub fn get_fallback_settings(&self) -> FallbackSettings {
+        FallbackSettings {
+            temperature: self.config.temperature,
+            temperature_inc: self.config.temperature_inc,
+            entropy_thold: self.config.entropy_thold,
+            logprob_thold: self.config.logprob_thold,
+            no_speech_thold: self.config.no_speech_thold,
+        }
+    }
+
+    pub fn set_temperature(
+        &mut self,
+        app: &tauri::AppHandle,
+        temperature: f32,
+    ) -> Result<(), String> {
+        check_range("temperature", temperature, 0.0..=1.0)?;
+        self.config.temperature = temperature;
+        self.emit_config(app);
+        Ok(())
+    }
+
+    pub fn set_temperature_inc(
+        &mut self,
+        app: &tauri::AppHandle,
+        temperature_inc: f32,
+    ) -> Result<(), String> {
+        check_range("temperature_inc", temperature_inc, 0.0..=1.0)?;
+        self.config.temperature_inc = temperature_inc;
+        self.emit_config(app);
+        Ok(())
+    }
+
+    pub fn set_entropy_thold(
+        &mut self,
+        app: &tauri::AppHandle,
+        entropy_thold: f32,
+    ) -> Result<(), String> {
+        check_range("entropy_thold", entropy_thold, 0.0..=10.0)?;
+        self.config.entropy_thold = entropy_thold;
+        self.emit_config(app);
+        Ok(())
+    }
+
+    pub fn set_logprob_thold(
+        &mut self,
+        app: &tauri::AppHandle,
+        logprob_thold: f32,
+    ) -> Result<(), String> {
+        check_range("logprob_thold", logprob_thold, -10.0..=0.0)?;
+        self.config.logprob_thold = logprob_thold;
+        self.emit_config(app);
+        Ok(())
+    }
+
+    pub fn set_no_speech_thold(
+        &mut self,
+        app: &tauri::AppHandle,
+        no_speech_thold: f32,
+    ) -> Result<(), String> {
+        check_range("no_speech_thold", no_speech_thold, 0.0..=1.0)?;
+        self.config.no_speech_thold = no_speech_thold;
+        self.emit_config(app);
+        Ok(())
+    }
+
+    pub fn get_word_timestamps(&self) -> bool {
+        self.config.word_timestamps
+    }
+
+    pub fn set_word_timestamps(&mut self, app: &tauri::AppHandle, word_timestamps: bool) {
+        self.config.word_timestamps = word_timestamps;
+        self.emit_config(app);
+    }
+
+    pub fn get_max_len(&self) -> i32 {
+        self.config.max_len.max(0)
+    }
+
+    pub fn set_max_len(&mut self, app: &tauri::AppHandle, max_len: i32) {
+        self.config.max_len = max_len;
+        self.emit_config(app);
+    }
+
+    // 日本語は単語の間に空白がないので、max_segment_charsで分け直す方を使う
+    pub fn get_split_on_word(&self) -> bool {
+        self.config.split_on_word
+    }
+
+    pub fn set_split_on_word(&mut self, app: &tauri::AppHandle, split_on_word: bool) {
+        self.config.split_on_word = split_on_word;
+        self.emit_config(app);
+    }
+
+    // 前の区間から引き継ぐテキストのトークン数。-1ならモデルの既定値を使う。
+    // はっきり文が区切れる放送のような音声では0にすると15〜30%ほど速くなる
+    pub fn get_max_context_tokens(&self) -> i32 {
+        self.config.max_context_tokens
+    }
+
+    pub fn set_max_context_tokens(
+        &mut self,
+        app: &tauri::AppHandle,
+        max_context_tokens: i32,
+    ) -> Result<(), String> {
+        if max_context_tokens != -1 && !(0..=MAX_CONTEXT_TOKENS).contains(&max_context_tokens) {
+            return Err(format!(
+                "max_context_tokensは-1か0〜{}の範囲で指定してください: {}",
+                MAX_CONTEXT_TOKENS, max_context_tokens
+            ));
+        }
+        self.config.max_context_tokens = max_context_tokens;
+        self.emit_config(app);
+        Ok(())
+    }
+
+    // エンコーダーが見る音声フレーム数。Noneなら音声の長さから決める
+    // (1秒あたりおよそ50フレーム)。短い音声では小さくするほど速くなる
+    pub fn get_audio_ctx(&self, ms_duration: i32) -> u32 {
+        self.config.audio_ctx.unwrap_or_else(|| {
+            MAX_AUDIO_CTX.min((ms_duration.max(0) as f64 / 1000.0 * 50.0) as u32)
+        })
+    }
+
+    pub fn set_audio_ctx(
+        &mut self,
+        app: &tauri::AppHandle,
+        audio_ctx: Option<u32>,
+    ) -> Result<(), String> {
+        if let Some(audio_ctx) = audio_ctx.filter(|ctx| !(1..=MAX_AUDIO_CTX).contains(ctx)) {
+            return Err(format!(
+                "audio_ctxは1〜{}の範囲で指定してください: {}",
+                MAX_AUDIO_CTX, audio_ctx
+            ));
+        }
+        self.config.audio_ctx = audio_ctx;
+        self.emit_config(app);
+        Ok(())
+    }
+
+    pub fn get_max_segment_chars(&self) -> Option<usize> {
+        self.config.max_segment_chars
+    }
+
+    pub fn set_max_segment_chars(
+        &mut self,
+        app: &tauri::AppHandle,
+        max_segment_chars: Option<usize>,
+    ) {
+        self.config.max_segment_chars = max_segment_chars;
+        self.emit_config(app);
+    }
+
+    // Noneならモデルを自動で解放しない
+    pub fn get_model_idle_timeout(&self) -> Option<Duration> {
+        self.config
+            .model_idle_timeout_min
+            .map(|min| Duration::from_secs(min as u64 * 60))
+    }
+
+    pub fn set_model_idle_timeout_min(
+        &mut self,
+        app: &tauri::AppHandle,
+        model_idle_timeout_min: Option<u32>,
+    ) {
+        self.config.model_idle_timeout_min = model_idle_timeout_min;
+        self.emit_config(app);
+    }
+
+    pub fn get_suppress_tokens(&self) -> &[i32] {
+        &self.config.suppress_tokens
+    }
+
+    pub fn set_suppress_tokens(&mut self, app: &tauri::AppHandle, suppress_tokens: Vec<i32>) {
+        self.config.suppress_tokens = suppress_tokens;
+        self.emit_config(app);
+    }
+
+    pub fn get_suppress_blank(&self) -> bool {
+        self.config.suppress_blank
+    }
+
+    pub fn set_suppress_blank(&mut self, app: &tauri::AppHandle, suppress_blank: bool) {
+        self.config.suppress_blank = suppress_blank;
+        self.emit_config(app);
+    }
+
+    pub fn get_repeat_filter(&self) -> RepeatFilter {
+        self.config.repeat_filter
+    }
+
+    pub fn set_repeat_filter(&mut self, app: &tauri::AppHandle, repeat_filter: RepeatFilter) {
+        self.config.repeat_filter = repeat_filter;
+        self.emit_config(app);
+    }
+
+    // 直近repeat_window件のうちrepeat_threshold件より多く同じ文が続いたら繰り返しとみなす
+    pub fn set_repeat_limits(
+        &mut self,
+        app: &tauri::AppHandle,
+        repeat_window: Option<usize>,
+        repeat_threshold: Option<usize>,
+    ) -> Result<(), String> {
+        let repeat_window = repeat_window.unwrap_or(self.config.repeat_window);
+        let repeat_threshold = repeat_threshold.unwrap_or(self.config.repeat_threshold);
+        if repeat_threshold == 0 || repeat_threshold > repeat_window {
+            return Err(format!(
+                "repeat_thresholdは1〜repeat_window({})の範囲で指定してください: {}",
+                repeat_window, repeat_threshold
+            ));
+        }
+        self.config.repeat_window = repeat_window;
+        self.config.repeat_threshold = repeat_threshold;
+        self.emit_config(app);
+        Ok(())
+    }
+
+    // 直近のセグメントに同じ文がしきい値より多くあるか。
+    // recentを渡したときはStoreのセグメントの代わりにそちらと比べる
+    pub fn is_repeated(&self, recent: Option<&[Data]>, subtitle: &str) -> bool {
+        recent
+            .unwrap_or(&self.data)
+            .iter()
+            .rev()
+            .take(self.config.repeat_window)
+            .filter(|d| is_near_duplicate(&d.subtitle, subtitle))
+            .count()
+            >= self.config.repeat_threshold
+    }
+
+    // 長い無音を飛ばして、発話のある区間だけを文字起こしする
+    pub fn get_vad(&self) -> Option<usize> {
+        self.config.vad.then_some(self.config.vad_min_silence_ms)
+    }
+
+    pub fn set_vad(&mut self, app: &tauri::AppHandle, vad: bool, min_silence_ms: Option<usize>) {
+        self.config.vad = vad;
+        if let Some(min_silence_ms) = min_silence_ms {
+            self.config.vad_min_silence_ms = min_silence_ms;
+        }
+        self.emit_config(app);
+    }
+
+    // 文字起こしのあと、間隔がこれより短いセグメントをつなげる
+    pub fn get_gap_merge_ms(&self) -> Option<u64> {
+        self.config.gap_merge_ms
+    }
+
+    pub fn set_gap_merge_ms(&mut self, app: &tauri::AppHandle, gap_merge_ms: Option<u64>) {
+        self.config.gap_merge_ms = gap_merge_ms;
+        self.emit_config(app);
+    }
+
+    // 文字起こし中にwhisper-metricsイベントで処理速度を送る
+    pub fn get_metrics(&self) -> bool {
+        self.config.metrics
+    }
+
+    pub fn set_metrics(&mut self, app: &tauri::AppHandle, metrics: bool) {
+        self.config.metrics = metrics;
+        self.emit_config(app);
+    }
+
+    // GPUを使わない設定にしたときやデバイスを変えたときは、読み込み済みのコンテキストを作り直す。
+    // set_path_modelと同じく、変わったかどうかを返してキャッシュを捨てるのは呼び出し側に任せる
+    pub fn set_use_gpu(&mut self, app: &tauri::AppHandle, use_gpu: bool) -> bool {
+        let changed = self.config.use_gpu != use_gpu;
+        self.config.use_gpu = use_gpu;
+        self.emit_config(app);
+        changed
+    }
+
+    pub fn get_gpu_settings(&self) -> GpuSettings {
+        GpuSettings {
+            use_gpu: self.config.use_gpu,
+            gpu_device: self.config.gpu_device,
+        }
+    }
+
+    pub fn set_gpu_device(
+        &mut self,
+        app: &tauri::AppHandle,
+        gpu_device: i32,
+    ) -> Result<bool, String> {
+        if gpu_device < 0 {
+            return Err(format!(
+                "GPUの番号には0以上の値を指定してください: {}",
+                gpu_device
+            ));
+        }
+        let changed = self.config.gpu_device != gpu_device;
+        self.config.gpu_device = gpu_device;
+        self.emit_config(app);
+        Ok(changed)
+    }
+
     pub fn get_ms_offset(&self) -> i32 {
-        self.config.sec_start * 1000
+        (self.config.sec_start * 1000.).round() as i32
     }
 
     pub fn get_ms_duration(&self) -> i32 {
         if self.config.sec_start > self.config.sec_end {
             0
         } else {
-            (self.config.sec_end - self.config.sec_start) * 1000
+            ((self.config.sec_end - self.config.sec_start) * 1000.).round() as i32
         }
     }
 
-    pub fn push_data(
+    pub fn push(&mut self, app: &tauri::AppHandle, data: Data) {
+        self.edit(app, "push", self.data.len(), 0, vec![data]);
+    }
+
+    // start_ms〜end_msを文字起こしし直したsegmentsで、その範囲に重なるセグメントを置き換える。
+    // 範囲の端にかかるセグメントは、中央の時刻が範囲に入っていれば置き換え、
+    // そうでなければ残して範囲の外側まで縮める。置き換えた数を返す
+    pub fn splice_range(
         &mut self,
         app: &tauri::AppHandle,
-        ms_start: i64,
-        ms_end: i64,
-        subtitle: String,
-    ) {
-        self.data.push(Data {
-            ms_start,
-            ms_end,
-            subtitle,
-        });
-        self.emit_data(app)
+        start_ms: i64,
+        end_ms: i64,
+        segments: Vec<Data>,
+    ) -> usize {
+        let index = self
+            .data
+            .iter()
+            .position(|d| d.ms_end > start_ms)
+            .unwrap_or(self.data.len());
+        let end = self.data[index..]
+            .iter()
+            .position(|d| d.ms_start >= end_ms)
+            .map_or(self.data.len(), |i| index + i);
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        for d in self.data[index..end].iter() {
+            let ms_mid = (d.ms_start + d.ms_end) / 2;
+            if ms_mid < start_ms {
+                let mut d = d.clone();
+                d.ms_end = start_ms;
+                d.words.retain(|word| word.t0 < start_ms);
+                before.push(d);
+            } else if ms_mid >= end_ms {
+                let mut d = d.clone();
+                d.ms_start = end_ms;
+                d.words.retain(|word| word.t1 > end_ms);
+                after.push(d);
+            }
+        }
+        let replaced = end - index - before.len() - after.len();
+        // 自動でつなげる設定なら、入れ替えと同じ1回の操作の中でつなげる
+        let segments = match self.config.gap_merge_ms {
+            Some(max_gap_ms) => merge_gaps(segments, max_gap_ms),
+            None => segments,
+        };
+        let inserted: Vec<Data> = before.into_iter().chain(segments).chain(after).collect();
+        self.edit(app, "retranscribe", index, end - index, inserted);
+        replaced
+    }
+
+    pub fn get_low_confidence_segments(&self, threshold: f32) -> Vec<LowConfidenceSegment> {
+        self.data
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.confidence < threshold)
+            .map(|(index, d)| LowConfidenceSegment {
+                index,
+                ms_start: d.ms_start,
+                ms_end: d.ms_end,
+                subtitle: d.subtitle.clone(),
+                confidence: d.confidence,
+            })
+            .collect()
+    }
+
+    // イベントを受け取り損ねた画面が結果を読み直すためのもの
+    pub fn get_all_segments(&self) -> Vec<SegmentData> {
+        self.data
+            .iter()
+            .enumerate()
+            .map(|(index, d)| segment_data(index, d))
+            .collect()
+    }
+
+    pub fn get_segment(&self, index: usize) -> Option<SegmentData> {
+        self.data.get(index).map(|d| segment_data(index, d))
+    }
+
+    fn segment_at(&self, index: usize) -> Result<&Data, String> {
+        self.data.get(index).ok_or_else(|| {
+            format!(
+                "セグメントの番号が範囲外です: {} / {}",
+                index,
+                self.data.len()
+            )
+        })
+    }
+
+    // regexクレートはバックトラックしないので、長い字幕でも検索が止まることはない
+    pub fn search_segments(
+        &self,
+        query: &str,
+        case_sensitive: bool,
+        regex: bool,
+    ) -> Result<Vec<SearchMatch>, String> {
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+        let matcher = build_matcher(query, case_sensitive, regex)?;
+        Ok(self
+            .data
+            .iter()
+            .enumerate()
+            .filter_map(|(index, d)| {
+                let ranges: Vec<(usize, usize)> = matcher
+                    .find_iter(&d.subtitle)
+                    .filter(|m| m.end() > m.start())
+                    .map(|m| (m.start(), m.end()))
+                    .collect();
+                (!ranges.is_empty()).then_some(SearchMatch {
+                    index,
+                    ms_start: d.ms_start,
+                    ms_end: d.ms_end,
+                    ranges,
+                })
+            })
+            .collect())
+    }
+
+    // 音声の長さはWAVのヘッダーから求め、読めなければ最後のセグメントの終わりまでとする
+    pub fn get_statistics(&self) -> Statistics {
+        let last_end = self.data.iter().map(|d| d.ms_end).max().unwrap_or(0).max(0) as u64;
+        let total_duration_ms = audio::wav_duration_ms(&self.config.path_wav)
+            .ok()
+            .filter(|&ms| ms > 0)
+            .unwrap_or(last_end);
+        // 重なっているセグメントを二重に数えないよう、開始時刻の順にたどる
+        let mut spans: Vec<(i64, i64)> = self
+            .data
+            .iter()
+            .map(|d| (d.ms_start.max(0), d.ms_end.max(0)))
+            .collect();
+        spans.sort();
+        let mut covered_ms = 0;
+        let mut covered_until = 0;
+        for (start, end) in spans {
+            let start = start.max(covered_until);
+            if end > start {
+                covered_ms += end - start;
+                covered_until = end;
+            }
+        }
+        let segment_count = self.data.len();
+        Statistics {
+            segment_count,
+            total_duration_ms,
+            word_count: self
+                .data
+                .iter()
+                .map(|d| d.subtitle.unicode_words().count())
+                .sum(),
+            avg_confidence: if segment_count == 0 {
+                0.
+            } else {
+                self.data.iter().map(|d| d.confidence).sum::<f32>() / segment_count as f32
+            },
+            coverage_pct: if total_duration_ms == 0 {
+                0.
+            } else {
+                (covered_ms as f32 * 100. / total_duration_ms as f32).min(100.)
+            },
+        }
+    }
+
+    // 大文字と小文字を区別せずに探し、見つかったセグメントの番号だけを返す
+    pub fn find_segments(&self, query: &str) -> Vec<usize> {
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let query = query.to_lowercase();
+        self.data
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.subtitle.to_lowercase().contains(&query))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    // すべてのセグメントで置き換え、置き換えた数の合計を返す
+    pub fn find_and_replace(
+        &mut self,
+        app: &tauri::AppHandle,
+        query: &str,
+        replacement: &str,
+    ) -> Result<usize, String> {
+        Ok(self
+            .replace_in_segments(app, query, replacement, false, None)?
+            .iter()
+            .map(|replaced| replaced.count)
+            .sum())
+    }
+
+    // 置き換えたセグメントは単語の区切りが変わり得るので、単語ごとの時刻は捨てる。
+    // 全体で1つの操作として記録するので、1回の取り消しで元に戻る
+    pub fn replace_in_segments(
+        &mut self,
+        app: &tauri::AppHandle,
+        query: &str,
+        replacement: &str,
+        regex: bool,
+        range: Option<(i64, i64)>,
+    ) -> Result<Vec<ReplaceCount>, String> {
+        if query.is_empty() {
+            return Err("置き換える文字列を指定してください".to_string());
+        }
+        let matcher = build_matcher(query, true, regex)?;
+        let mut replaced = self.data.clone();
+        let mut counts = Vec::new();
+        for (index, d) in replaced.iter_mut().enumerate() {
+            if range.is_some_and(|(start, end)| d.ms_start < start || d.ms_end > end) {
+                continue;
+            }
+            let count = matcher
+                .find_iter(&d.subtitle)
+                .filter(|m| m.end() > m.start())
+                .count();
+            if count == 0 {
+                continue;
+            }
+            // 正規表現のときだけ$1などの参照を展開する
+            let subtitle = if regex {
+                matcher.replace_all(&d.subtitle, replacement)
+            } else {
+                matcher.replace_all(&d.subtitle, NoExpand(replacement))
+            }
+            .into_owned();
+            d.subtitle = subtitle;
+            d.words.clear();
+            d.edited = true;
+            counts.push(ReplaceCount { index, count });
+        }
+        if !counts.is_empty() {
+            let len = self.data.len();
+            self.edit(app, "replace", 0, len, replaced);
+        }
+        Ok(counts)
+    }
+
+    pub fn segments_below_confidence(&self, threshold: f32) -> Vec<usize> {
+        self.data
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.confidence < threshold)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    // tinydiarizeの話者交代フラグごとに話者番号を進める。非tdrzモデルでは全員が話者1になる
+    pub fn get_segments_with_speakers(&self) -> Vec<SpeakerSegment> {
+        let mut speaker = 1;
+        self.data
+            .iter()
+            .enumerate()
+            .map(|(index, d)| {
+                let segment = SpeakerSegment {
+                    index,
+                    ms_start: d.ms_start,
+                    ms_end: d.ms_end,
+                    subtitle: d.subtitle.clone(),
+                    speaker: format!("Speaker {}", speaker),
+                };
+                if d.speaker_turn {
+                    speaker += 1;
+                }
+                segment
+            })
+            .collect()
+    }
+
+    pub fn clear(&mut self, app: &tauri::AppHandle, keep_config: bool) {
+        if !keep_config {
+            self.config = Config::default();
+            self.emit_config(app);
+        }
+        self.clear_data(app);
+    }
+
+    pub fn update_segment(
+        &mut self,
+        app: &tauri::AppHandle,
+        index: usize,
+        new_text: String,
+    ) -> Result<(), String> {
+        let text = new_text.trim();
+        if text.is_empty() {
+            return Err("字幕を空にすることはできません".to_string());
+        }
+        let mut data = self.segment_at(index)?.clone();
+        data.subtitle = text.to_string();
+        // 単語ごとの時刻は元の文に対するものなので、書き換えたら使えない
+        data.words.clear();
+        data.edited = true;
+        self.edit(app, "update", index, 1, vec![data]);
+        Ok(())
+    }
+
+    pub fn update_segment_timing(
+        &mut self,
+        app: &tauri::AppHandle,
+        index: usize,
+        t0: i64,
+        t1: i64,
+    ) -> Result<(), String> {
+        if t0 < 0 || t0 >= t1 {
+            return Err(format!("開始と終了の時刻が不正です: {} --> {}", t0, t1));
+        }
+        let mut data = self.segment_at(index)?.clone();
+        data.ms_start = t0;
+        data.ms_end = t1;
+        data.edited = true;
+        self.edit(app, "timing", index, 1, vec![data]);
+        Ok(())
+    }
+
+    // 隣り合う2つのセグメントを1つにまとめる
+    pub fn merge_segments(
+        &mut self,
+        app: &tauri::AppHandle,
+        a: usize,
+        b: usize,
+    ) -> Result<(), String> {
+        if b != a + 1 {
+            return Err("隣り合うセグメントしか結合できません".to_string());
+        }
+        if b >= self.data.len() {
+            return Err(format!(
+                "セグメントの番号が範囲外です: {} / {}",
+                b,
+                self.data.len()
+            ));
+        }
+        let mut first = self.data[a].clone();
+        merge_data(&mut first, self.data[b].clone());
+        first.edited = true;
+        self.edit_in_order(app, "merge", a, 2, vec![first]);
+        Ok(())
+    }
+
+    // 間隔がmax_gap_msより短いセグメントをつなげる。まとめて1回の操作として記録し、つなげた数を返す
+    pub fn merge_by_gap(&mut self, app: &tauri::AppHandle, max_gap_ms: u64) -> usize {
+        let merged = merge_gaps(self.data.clone(), max_gap_ms);
+        let count = self.data.len() - merged.len();
+        if count > 0 {
+            let len = self.data.len();
+            self.edit(app, "merge_gap", 0, len, merged);
+        }
+        count
+    }
+
+    // char_offset（バイト位置）で字幕を2つに分ける。at_msがなければ時間は文字数の比率で割り振る
+    pub fn split_segment(
+        &mut self,
+        app: &tauri::AppHandle,
+        index: usize,
+        char_offset: usize,
+        at_ms: Option<i64>,
+    ) -> Result<(), String> {
+        let data = self.segment_at(index)?;
+        let subtitle = &data.subtitle;
+        if char_offset == 0
+            || char_offset >= subtitle.len()
+            || !subtitle.is_char_boundary(char_offset)
+        {
+            return Err(format!("分割する位置が不正です: {}", char_offset));
+        }
+        let (head, tail) = subtitle.split_at(char_offset);
+        if head.trim().is_empty() || tail.trim().is_empty() {
+            return Err("分割すると空の字幕ができてしまいます".to_string());
+        }
+        let ms_split = match at_ms {
+            Some(at_ms) if at_ms <= data.ms_start || at_ms >= data.ms_end => {
+                return Err(format!("分割する時刻がセグメントの外です: {}", at_ms));
+            }
+            Some(at_ms) => at_ms,
+            None => {
+                let total_chars = subtitle.chars().count() as i64;
+                data.ms_start
+                    + (data.ms_end - data.ms_start) * head.chars().count() as i64 / total_chars
+            }
+        };
+        // 単語は中央の時刻がどちら側にあるかで振り分ける
+        let (head_words, tail_words) = data
+            .words
+            .iter()
+            .cloned()
+            .partition(|word| (word.t0 + word.t1) / 2 < ms_split);
+        let first = Data {
+            ms_end: ms_split,
+            subtitle: head.trim().to_string(),
+            words: head_words,
+            speaker_turn: false,
+            edited: true,
+            ..data.clone()
+        };
+        let second = Data {
+            ms_start: ms_split,
+            subtitle: tail.trim().to_string(),
+            words: tail_words,
+            edited: true,
+            ..data.clone()
+        };
+        self.edit_in_order(app, "split", index, 1, vec![first, second]);
+        Ok(())
+    }
+
+    // セグメントが開始時刻の順に並んでいるか
+    pub fn check_order(&self) -> bool {
+        self.data
+            .windows(2)
+            .all(|pair| pair[0].ms_start <= pair[1].ms_start)
+    }
+
+    // 開始時刻の順に並べ直す。番号は並び順なので振り直しになる。
+    // 入れ替わった範囲だけを1回の操作として記録し、並べ替えたかどうかを返す
+    pub fn reorder_segments(&mut self, app: &tauri::AppHandle) -> bool {
+        if self.check_order() {
+            return false;
+        }
+        let mut order: Vec<usize> = (0..self.data.len()).collect();
+        order.sort_by_key(|&i| self.data[i].ms_start);
+        let moved = |(to, &from): (usize, &usize)| to != from;
+        let (Some(first), Some(last)) = (
+            order.iter().enumerate().position(moved),
+            order.iter().enumerate().rposition(moved),
+        ) else {
+            return false;
+        };
+        let inserted = order[first..=last]
+            .iter()
+            .map(|&i| self.data[i].clone())
+            .collect();
+        self.edit(app, "reorder", first, last - first + 1, inserted);
+        true
+    }
+
+    // 読み込んだセグメントで全体を入れ替える。開始時刻の順に並べてから1回の操作として記録するので、
+    // 1回の取り消しで元に戻る
+    pub fn replace_segments(&mut self, app: &tauri::AppHandle, mut data: Vec<Data>) {
+        data.sort_by_key(|d| d.ms_start);
+        let len = self.data.len();
+        self.edit(app, "import", 0, len, data);
+    }
+
+    // 全体（rangeがあればrangeに収まるセグメントだけ）の時刻をdelta_msずらす。0より前は0に詰める
+    pub fn shift_segments(
+        &mut self,
+        app: &tauri::AppHandle,
+        delta_ms: i64,
+        range: Option<(i64, i64)>,
+    ) -> Result<(), String> {
+        let in_range =
+            |d: &Data| range.is_none_or(|(start, end)| d.ms_start >= start && d.ms_end <= end);
+        let shift = |ms: i64| (ms + delta_ms).max(0);
+        let mut shifted = self.data.clone();
+        for (index, d) in shifted.iter_mut().enumerate() {
+            if !in_range(d) {
+                continue;
+            }
+            d.ms_start = shift(d.ms_start);
+            d.ms_end = shift(d.ms_end);
+            if d.ms_end <= d.ms_start {
+                return Err(format!(
+                    "ずらすとセグメントの長さが0以下になります: {}",
+                    index
+                ));
+            }
+            for word in d.words.iter_mut() {
+                word.t0 = shift(word.t0);
+                word.t1 = shift(word.t1);
+            }
+        }
+        let len = self.data.len();
+        self.edit(app, "shift", 0, len, shifted);
+        Ok(())
     }
 
     pub fn clear_data(&mut self, app: &tauri::AppHandle) {
         self.data = Vec::new();
+        self.clear_history();
+        self.emit_data(app)
+    }
+
+    // 一括処理でファイルごとに消す前に、いまの文字起こし結果を履歴ごと取り出す
+    pub fn take_transcript(&mut self) -> SavedTranscript {
+        SavedTranscript {
+            data: std::mem::take(&mut self.data),
+            undo_stack: std::mem::take(&mut self.undo_stack),
+            redo_stack: std::mem::take(&mut self.redo_stack),
+        }
+    }
+
+    pub fn restore_transcript(&mut self, app: &tauri::AppHandle, saved: SavedTranscript) {
+        self.data = saved.data;
+        self.undo_stack = saved.undo_stack;
+        self.redo_stack = saved.redo_stack;
         self.emit_data(app)
     }
 
-    fn emit_config(&self, app: &tauri::AppHandle) {
+    // 別のファイルの履歴と混ざらないよう、新しい文字起こしを始めるときに消す
+    pub fn clear_history(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+    }
+
+    // dataへの変更はすべてここを通し、取り消し用の履歴に積む
+    fn edit(
+        &mut self,
+        app: &tauri::AppHandle,
+        op: &'static str,
+        index: usize,
+        remove_len: usize,
+        inserted: Vec<Data>,
+    ) {
+        let removed = self
+            .data
+            .splice(index..index + remove_len, inserted.clone())
+            .collect();
+        let edit = Edit {
+            op,
+            index,
+            removed,
+            inserted,
+        };
+        self.emit_store_changed(app, &edit);
+        if self.undo_stack.len() >= MAX_UNDO {
+            self.undo_stack.pop_front();
+        }
+        self.undo_stack.push_back(edit);
+        self.redo_stack.clear();
+        self.emit_data(app);
+    }
+
+    // editと同じだが、開始時刻の順に並ぶよう並べ直し、入れ替わった範囲も含めて1回の操作として記録する
+    fn edit_in_order(
+        &mut self,
+        app: &tauri::AppHandle,
+        op: &'static str,
+        index: usize,
+        remove_len: usize,
+        inserted: Vec<Data>,
+    ) {
+        // 元の位置を覚えておき、動かなかった先頭と末尾は操作に含めない
+        let old_len = self.data.len();
+        let mut after: Vec<(Option<usize>, Data)> = (0..index)
+            .map(Some)
+            .chain(std::iter::repeat_n(None, inserted.len()))
+            .chain((index + remove_len..old_len).map(Some))
+            .zip(
+                self.data[..index]
+                    .iter()
+                    .cloned()
+                    .chain(inserted)
+                    .chain(self.data[index + remove_len..].iter().cloned()),
+            )
+            .collect();
+        after.sort_by_key(|(_, d)| d.ms_start);
+        let new_len = after.len();
+        let prefix = after
+            .iter()
+            .enumerate()
+            .take_while(|&(i, (from, _))| *from == Some(i))
+            .count();
+        let suffix = after[prefix..]
+            .iter()
+            .rev()
+            .enumerate()
+            .take_while(|&(i, (from, _))| *from == Some(old_len - 1 - i))
+            .count()
+            .min(old_len - prefix);
+        let inserted = after
+            .drain(prefix..new_len - suffix)
+            .map(|(_, d)| d)
+            .collect();
+        self.edit(app, op, prefix, old_len - prefix - suffix, inserted);
+    }
+
+    pub fn undo(&mut self, app: &tauri::AppHandle) -> Result<(), String> {
+        let edit = self
+            .undo_stack
+            .pop_back()
+            .ok_or("取り消せる操作がありません")?;
+        let index = edit.index;
+        self.data
+            .splice(index..index + edit.inserted.len(), edit.removed.clone());
+        self.emit_store_changed(
+            app,
+            &Edit {
+                op: "undo",
+                index,
+                removed: edit.inserted.clone(),
+                inserted: edit.removed.clone(),
+            },
+        );
+        self.redo_stack.push(edit);
+        self.emit_data(app);
+        Ok(())
+    }
+
+    pub fn redo(&mut self, app: &tauri::AppHandle) -> Result<(), String> {
+        let edit = self.redo_stack.pop().ok_or("やり直せる操作がありません")?;
+        let index = edit.index;
+        self.data
+            .splice(index..index + edit.removed.len(), edit.inserted.clone());
+        self.emit_store_changed(
+            app,
+            &Edit {
+                op: "redo",
+                ..edit.clone()
+            },
+        );
+        self.undo_stack.push_back(edit);
+        self.emit_data(app);
+        Ok(())
+    }
+
+    pub fn cues(&self) -> Vec<Cue> {
+        self.data
+            .iter()
+            .zip(self.get_segments_with_speakers())
+            .map(|(d, segment)| Cue {
+                index: segment.index,
+                ms_start: d.ms_start,
+                ms_end: d.ms_end,
+                text: d.subtitle.clone(),
+                words: d.words.clone(),
+                speaker: segment.speaker,
+                speaker_turn: d.speaker_turn,
+                confidence: d.confidence,
+                edited: d.edited,
+            })
+            .collect()
+    }
+
+    fn emit_config(&mut self, app: &tauri::AppHandle) {
+        self.dirty = true;
+        self.last_change = Some(Instant::now());
         dbg!(&self.config);
         app.emit_all("config", self.config.clone()).unwrap();
     }
 
-    fn emit_data(&self, app: &tauri::AppHandle) {
+    fn emit_store_changed(&self, app: &tauri::AppHandle, edit: &Edit) {
+        let _ = app.emit_all(
+            "store_changed",
+            StoreChangedPayload {
+                op: edit.op.to_string(),
+                index: edit.index,
+                removed: edit.removed.len(),
+                inserted: edit.inserted.len(),
+                count: self.data.len(),
+            },
+        );
+    }
+
+    fn emit_data(&mut self, app: &tauri::AppHandle) {
+        self.dirty = true;
+        self.last_change = Some(Instant::now());
         app.emit_all(
             "data",
             self.data
@@ -177,29 +1871,175 @@ impl Store {
                 .join("\n"),
         )
         .unwrap();
-        let sec = self.data.last().map_or(0, |d| d.ms_end / 1000) as i32;
+        let sec = self.data.last().map_or(0., |d| d.ms_end as f64 / 1000.);
         app.emit_all(
             "progress",
-            if self.config.sec_end <= 0 {
+            if self.config.sec_end <= 0. {
                 0
             } else if sec > self.config.sec_end {
                 100
             } else {
-                sec * 100 / self.config.sec_end
+                (sec * 100. / self.config.sec_end) as i32
             },
         )
         .unwrap();
     }
 }
 
-fn ts(ms: i64) -> String {
-    format!(
-        "{:0>2}:{:0>2}:{:0>2}.{:0>3}",
-        ms / 3600000,
-        (ms % 3600000) / 60000,
-        (ms % 60000) / 1000,
-        ms % 1000
-    )
+fn seconds_to_ms(name: &str, seconds: f64) -> Result<i32, String> {
+    let ms = (seconds * 1000.0).round();
+    if !ms.is_finite() || ms < 0. || ms > i32::MAX as f64 {
+        return Err(format!(
+            "{}には0以上の秒数を指定してください: {}",
+            name, seconds
+        ));
+    }
+    Ok(ms as i32)
+}
+
+// regexがfalseなら文字列そのものを探す
+fn build_matcher(query: &str, case_sensitive: bool, regex: bool) -> Result<Regex, String> {
+    let pattern = if regex {
+        query.to_string()
+    } else {
+        regex::escape(query)
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(!case_sensitive)
+        .size_limit(SEARCH_REGEX_SIZE_LIMIT)
+        .dfa_size_limit(SEARCH_REGEX_SIZE_LIMIT)
+        .build()
+        .map_err(|err| format!("検索条件が不正です: {}", err))
+}
+
+fn segment_data(index: usize, d: &Data) -> SegmentData {
+    SegmentData {
+        index,
+        t0_ms: d.ms_start,
+        t1_ms: d.ms_end,
+        text: d.subtitle.clone(),
+        confidence: d.confidence,
+        edited: d.edited,
+        suspect: d.suspect,
+    }
+}
+
+// 空白と句読点を除いて比べ、短い方が長い方の8割以上を占めていれば同じ文とみなす
+fn is_near_duplicate(a: &str, b: &str) -> bool {
+    let normalize = |text: &str| -> String {
+        text.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect()
+    };
+    let (a, b) = (normalize(a), normalize(b));
+    let (shorter, longer) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    !shorter.is_empty() && longer.contains(&shorter) && shorter.len() * 10 >= longer.len() * 8
+}
+
+fn check_range(name: &str, value: f32, range: RangeInclusive<f32>) -> Result<(), String> {
+    if range.contains(&value) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{}は{}〜{}の範囲で指定してください: {}",
+            name,
+            range.start(),
+            range.end(),
+            value
+        ))
+    }
+}
+
+pub fn session_path(app: &tauri::AppHandle) -> Option<PathBuf> {
+    tauri::api::path::app_data_dir(&app.config()).map(|dir| dir.join("pothook/session.json"))
+}
+
+// 正常に終了したときは消すので、起動時に残っていれば前回は異常終了している
+pub fn autosave_path(app: &tauri::AppHandle) -> Option<PathBuf> {
+    tauri::api::path::app_data_dir(&app.config()).map(|dir| dir.join("pothook/autosave.json"))
+}
+
+pub fn start_autosave(app: tauri::AppHandle) {
+    let Some(path) = autosave_path(&app) else {
+        return;
+    };
+    thread::spawn(move || loop {
+        thread::sleep(AUTOSAVE_CHECK_INTERVAL);
+        let Ok(mut store) = STORE.lock() else {
+            return;
+        };
+        if store.autosave_due() {
+            match store.save(&path) {
+                Ok(()) => store.last_change = None,
+                Err(err) => tracing::warn!("failed to autosave: {}", err),
+            }
+        }
+    });
 }
 
 pub static STORE: Lazy<Mutex<Store>> = Lazy::new(|| Mutex::new(Store::new()));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 重なった区間で同じ文がもう一度出てきたときは、句読点や大文字小文字が違っても同じとみなす
+    #[test]
+    fn near_duplicate_across_overlapping_windows() {
+        assert!(is_near_duplicate("Nice to meet you.", " nice to meet you"));
+        assert!(is_near_duplicate(
+            "今日はいい天気ですね。",
+            "今日はいい天気ですね"
+        ));
+        assert!(is_near_duplicate("nice to meet you", "Nice to meet you!!"));
+    }
+
+    #[test]
+    fn near_duplicate_rejects_partial_or_empty() {
+        // 後半だけが重なっているものは別の文として残す
+        assert!(!is_near_duplicate("to meet you", "nice to meet you"));
+        assert!(!is_near_duplicate("hello", "goodbye"));
+        assert!(!is_near_duplicate("...", "..."));
+    }
+
+    #[test]
+    fn merge_gaps_joins_with_a_space_and_weights_confidence() {
+        let data = |ms_start, ms_end, subtitle: &str, confidence, speaker_turn| {
+            Data::new(
+                ms_start,
+                ms_end,
+                subtitle.to_string(),
+                Vec::new(),
+                confidence,
+                speaker_turn,
+                false,
+            )
+        };
+        let merged = merge_gaps(
+            vec![
+                data(0, 3000, "今日は", 0.9, false),
+                data(3100, 4100, "晴れです", 0.5, true),
+                data(4200, 5000, "次の人", 0.8, false),
+            ],
+            200,
+        );
+        // 話者が替わるところはつなげない
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].subtitle, "今日は 晴れです");
+        assert_eq!((merged[0].ms_start, merged[0].ms_end), (0, 4100));
+        assert!((merged[0].confidence - 0.8).abs() < 1e-6);
+        assert!(merged[0].speaker_turn);
+        // 自動でつなげただけなので手で直したことにはしない
+        assert!(!merged[0].edited);
+
+        let merged = merge_gaps(
+            vec![
+                data(0, 1000, " Hello", 1., false),
+                data(1050, 2000, " world", 1., false),
+            ],
+            100,
+        );
+        assert_eq!(merged[0].subtitle, "Hello world");
+    }
+}
@@ -0,0 +1,99 @@
+use crate::whisper::ModelSize;
+use std::collections::BTreeMap;
+
+// 設定画面でGPUの切り替えを使えるか、どのモデルを勧めるかを決めるための情報
+#[derive(Clone, serde::Serialize, Debug, Default)]
+pub struct SystemInfo {
+    avx: bool,
+    avx2: bool,
+    neon: bool,
+    metal: bool,
+    cuda: bool,
+    coreml: bool,
+    // GPUの切り替えが意味を持つか（CUDAかMetalが組み込まれているか）
+    gpu_available: bool,
+    // 上にないフラグ。whisper.cppの版によって増えるので、名前と値をそのまま入れる
+    extra: BTreeMap<String, String>,
+    // 取得できないOSではNone
+    total_ram_bytes: Option<u64>,
+    cpu_cores: usize,
+    cpu_threads: usize,
+    recommended_model: Option<ModelSize>,
+}
+
+impl SystemInfo {
+    pub fn backend(&self, use_gpu: bool) -> &'static str {
+        match use_gpu {
+            true if self.cuda => "CUDA",
+            true if self.metal => "Metal",
+            _ => "CPU",
+        }
+    }
+}
+
+pub fn get() -> SystemInfo {
+    let total_ram_bytes = total_ram_bytes();
+    SystemInfo {
+        total_ram_bytes,
+        cpu_cores: num_cpus::get_physical(),
+        cpu_threads: num_cpus::get(),
+        recommended_model: total_ram_bytes.map(recommend_model),
+        ..parse(whisper_rs::print_system_info())
+    }
+}
+
+// "AVX = 1 | AVX2 = 1 | NEON = 0 | ..." の形の文字列を読む。形が崩れた項目は飛ばす
+pub fn parse(text: &str) -> SystemInfo {
+    let mut info = SystemInfo::default();
+    for flag in text.split('|') {
+        let Some((name, value)) = flag.split_once('=') else {
+            continue;
+        };
+        let (name, value) = (name.trim(), value.trim());
+        let enabled = value == "1";
+        match name {
+            "AVX" => info.avx = enabled,
+            "AVX2" => info.avx2 = enabled,
+            "NEON" => info.neon = enabled,
+            "METAL" => info.metal = enabled,
+            // 古いwhisper.cppはCUBLAS、新しいものはCUDAと書く
+            "CUDA" | "CUBLAS" => info.cuda |= enabled,
+            "COREML" => info.coreml = enabled,
+            "" => (),
+            _ => {
+                info.extra.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+    info.gpu_available = info.cuda || info.metal;
+    info
+}
+
+#[cfg(unix)]
+fn total_ram_bytes() -> Option<u64> {
+    let (pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    (pages > 0 && page_size > 0).then(|| pages as u64 * page_size as u64)
+}
+
+#[cfg(not(unix))]
+fn total_ram_bytes() -> Option<u64> {
+    None
+}
+
+// OSや他のアプリの分を残すため、メモリの半分に収まる一番大きいモデルを勧める
+fn recommend_model(total_ram_bytes: u64) -> ModelSize {
+    [
+        ModelSize::Large,
+        ModelSize::Medium,
+        ModelSize::Small,
+        ModelSize::Base,
+    ]
+    .into_iter()
+    .find(|size| size.ram_bytes() <= total_ram_bytes / 2)
+    .unwrap_or(ModelSize::Tiny)
+}
@@ -1,50 +1,669 @@
-use crate::store::STORE;
-use libc::c_void;
+use crate::audio;
+pub use crate::error::WhisperError;
+use crate::messages;
+use crate::model_cache::MODEL_CACHE;
+use crate::models;
+use crate::store::{
+    ConfigError, Data, FallbackSettings, RepeatFilter, SamplingStrategyConfig, SegmentData, Word,
+    STORE,
+};
+use libc::{c_int, c_void};
+use once_cell::sync::Lazy;
 use std::ffi::CStr;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tauri::Manager;
-use whisper_rs::{
-    FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters,
-};
+use whisper_rs::{FullParams, SamplingStrategy, WhisperState};
+
+const MAX_PROMPT_TOKENS: usize = 224;
+const METRICS_INTERVAL_MS: u64 = 2000;
 
 #[derive(Clone, serde::Serialize, Debug)]
 struct WhisperPayload {
     status: String,
+    // messageはcodeとparamsから作った文。表示は設定の言語になる
+    code: String,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    params: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_id: Option<u64>,
 }
 
-unsafe extern "C" fn whisper_callback(
+// 専用のペイロードにもcodeを付け、表示に使う値をparamsに入れて送る
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperSkippedPayload {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    segment: i32,
+    no_speech_prob: f32,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperLanguagePayload {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    lang: String,
+    probability: f32,
+    low_confidence: bool,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperSegmentUpdatedPayload {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    segment: SegmentData,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+pub struct WhisperLanguage {
+    code: String,
+    name: String,
+    selected: bool,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperStartPayload {
+    status: String,
+    run_id: u64,
+    code: String,
+    message: String,
+    n_threads: i32,
+    fallback: FallbackSettings,
+    duration_ms: i32,
+    // whisperが実際に初期化したバックエンド
+    backend: String,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperSegmentPayload {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    run_id: u64,
+    start_ms: i64,
+    end_ms: i64,
+    message: String,
+    confidence: f32,
+    suspect: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    words: Option<Vec<Word>>,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperProgressPayload {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    run_id: u64,
+    progress: i32,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperRunProgressPayload {
+    progress: i32,
+    elapsed_ms: u64,
+}
+
+struct CallbackData<A = tauri::AppHandle> {
+    app: A,
+    run_id: u64,
+    cancel: Arc<AtomicBool>,
+    progress_interval: i32,
+    started: Instant,
+    last_progress: AtomicI32,
+    no_speech_thold: f32,
+    word_timestamps: bool,
+    max_segment_chars: Option<usize>,
+    suppress_tokens: Vec<i32>,
+    time_base_ms: i64,
+    skip_before_ms: i64,
+    collected: Option<Arc<Mutex<Vec<Data>>>>,
+    repeat_filter: RepeatFilter,
+    dropped: AtomicUsize,
+    marked: AtomicUsize,
+    segments: AtomicUsize,
+    metrics: bool,
+    tokens: AtomicUsize,
+    last_metrics_ms: AtomicU64,
+}
+
+// 範囲を文字起こしし直すときの指定。セグメントはStoreに入れずにcollectedへためる
+struct RangeRun {
+    path_model: Option<PathBuf>,
+    collected: Arc<Mutex<Vec<Data>>>,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperSpeakerTurnPayload {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    // このセグメントのあとで話者が替わる
+    end_ms: i64,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperMetricsPayload {
+    run_id: u64,
+    tokens: usize,
+    audio_ms: i64,
+    elapsed_ms: u64,
+    tokens_per_sec: f32,
+    realtime_factor: f32,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperDonePayload {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    run_id: u64,
+    stats: RunStats,
+}
+
+// elapsed_msはstate.fullにかかった時間の合計
+#[derive(Clone, Default, serde::Serialize, Debug)]
+struct RunStats {
+    segment_count: usize,
+    // 繰り返しや無音として捨てた、または印を付けたセグメントの数
+    dropped: usize,
+    marked: usize,
+    audio_duration_ms: u64,
+    elapsed_ms: u64,
+    tokens: usize,
+    tokens_per_sec: f32,
+    // 処理時間を音声の長さで割った値。1より小さければ実時間より速い
+    realtime_factor: f32,
+    model_path: PathBuf,
+    lang: Option<String>,
+}
+
+impl RunStats {
+    // 区間ごとの結果を足していく。モデルと言語は最後の区間のものにする
+    fn add(&mut self, stats: RunStats) {
+        self.segment_count += stats.segment_count;
+        self.dropped += stats.dropped;
+        self.marked += stats.marked;
+        self.elapsed_ms += stats.elapsed_ms;
+        self.tokens += stats.tokens;
+        self.model_path = stats.model_path;
+        self.lang = stats.lang.or(self.lang.take());
+    }
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperSuppressedPayload {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    dropped: usize,
+    marked: usize,
+}
+
+// ライブ音声を区切って渡すときの1区間。time_base_msは区間の先頭の時刻で、
+// skip_before_msより前に終わるセグメントは前の区間で出力済みなので捨てる
+#[derive(Debug, Clone, Copy)]
+struct StreamWindow {
+    time_base_ms: i64,
+    skip_before_ms: i64,
+    // 実行の最初の区間。開始イベントや警告はこの区間でだけ送り、範囲の文字起こしし直しでなければ編集履歴も消す
+    first: bool,
+    // 開始イベントで送る実行全体の長さ。Noneならこの区間の長さを送る
+    run_duration_ms: Option<i32>,
+}
+
+#[derive(Clone, serde::Serialize, Debug)]
+struct WhisperVadPayload {
+    status: String,
+    code: String,
+    params: serde_json::Value,
+    regions: usize,
+    speech_ms: i64,
+    skipped_ms: i64,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelSize {
+    Tiny,
+    Base,
+    Small,
+    Medium,
+    Large,
+}
+
+impl ModelSize {
+    // 基準のCPUで音声の長さの何倍かかるか
+    fn realtime_factor(self) -> f64 {
+        match self {
+            ModelSize::Tiny => 0.2,
+            ModelSize::Base => 0.4,
+            ModelSize::Small => 0.8,
+            ModelSize::Medium => 1.8,
+            ModelSize::Large => 3.5,
+        }
+    }
+
+    // 文字起こし中に使うおおよそのメモリ（whisper.cppのREADMEの値）
+    pub fn ram_bytes(self) -> u64 {
+        const MB: u64 = 1024 * 1024;
+        match self {
+            ModelSize::Tiny => 273 * MB,
+            ModelSize::Base => 388 * MB,
+            ModelSize::Small => 852 * MB,
+            ModelSize::Medium => 2100 * MB,
+            ModelSize::Large => 3900 * MB,
+        }
+    }
+}
+
+pub fn estimate_duration_ms(audio_duration_ms: u64, model_size: ModelSize) -> u64 {
+    (audio_duration_ms as f64 * model_size.realtime_factor()) as u64
+}
+
+pub static CANCEL_FLAG: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
+// 中止した実行のイベントが遅れて届いても見分けられるよう、実行ごとに番号を振る
+static RUN_ID: AtomicU64 = AtomicU64::new(0);
+
+fn next_run_id() -> u64 {
+    RUN_ID.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+// falseを返すとwhisper側でエンコードを打ち切る
+unsafe extern "C" fn encoder_begin_callback(
+    _: *mut whisper_rs_sys::whisper_context,
+    _: *mut whisper_rs_sys::whisper_state,
+    user_data: *mut c_void,
+) -> bool {
+    !(*(user_data as *const CallbackData))
+        .cancel
+        .load(Ordering::SeqCst)
+}
+
+// 指定されたトークンが選ばれないようにロジットを-∞にする
+unsafe extern "C" fn logits_filter_callback(
+    ctx: *mut whisper_rs_sys::whisper_context,
+    _: *mut whisper_rs_sys::whisper_state,
+    _: *const whisper_rs_sys::whisper_token_data,
+    _: c_int,
+    logits: *mut f32,
+    user_data: *mut c_void,
+) {
+    let data = &*(user_data as *const CallbackData);
+    if data.suppress_tokens.is_empty() || logits.is_null() {
+        return;
+    }
+    let n_vocab = whisper_rs_sys::whisper_n_vocab(ctx);
+    let logits = std::slice::from_raw_parts_mut(logits, n_vocab.max(0) as usize);
+    for &id in &data.suppress_tokens {
+        if let Some(logit) = logits.get_mut(id as usize) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+// 進捗率が変わったときだけ通知する
+unsafe extern "C" fn progress_callback(
     _: *mut whisper_rs_sys::whisper_context,
+    _: *mut whisper_rs_sys::whisper_state,
+    progress: i32,
+    user_data: *mut c_void,
+) {
+    let data = &*(user_data as *const CallbackData);
+    if data.last_progress.swap(progress, Ordering::SeqCst) == progress {
+        return;
+    }
+    // whisperが数えた処理済みのフレームから出した進捗なので、セグメントが出る前のエンコード中も進む
+    if progress % data.progress_interval == 0 {
+        let _ = data.app.emit_all(
+            "whisper",
+            WhisperProgressPayload {
+                status: "progress".to_string(),
+                code: "progress".to_string(),
+                params: serde_json::json!({ "progress": progress }),
+                run_id: data.run_id,
+                progress,
+            },
+        );
+    }
+    let _ = data.app.emit_all(
+        "whisper_progress",
+        WhisperRunProgressPayload {
+            progress,
+            elapsed_ms: data.started.elapsed().as_millis() as u64,
+        },
+    );
+}
+
+// 特殊トークンを除いたトークン確率の幾何平均。トークンがなければNaNではなく0にする
+unsafe fn segment_confidence(
+    ctx: *mut whisper_rs_sys::whisper_context,
+    ptr: *mut whisper_rs_sys::whisper_state,
+    i_segment: i32,
+) -> f32 {
+    let token_eot = whisper_rs_sys::whisper_token_eot(ctx);
+    let probs: Vec<f32> = (0..whisper_rs_sys::whisper_full_n_tokens_from_state(ptr, i_segment))
+        .filter(|&i_token| {
+            whisper_rs_sys::whisper_full_get_token_id_from_state(ptr, i_segment, i_token)
+                < token_eot
+        })
+        .map(|i_token| whisper_rs_sys::whisper_full_get_token_p_from_state(ptr, i_segment, i_token))
+        .collect();
+    // 確率の幾何平均。1トークンでも確信の低いものがあれば全体の値も下がる
+    if probs.is_empty() {
+        return 0.;
+    }
+    let log_sum: f32 = probs.iter().map(|p| p.max(f32::MIN_POSITIVE).ln()).sum();
+    (log_sum / probs.len() as f32).exp()
+}
+
+// 特殊トークン(タイムスタンプ等)を除いたトークンを単語として取り出す
+unsafe fn segment_words(
+    ctx: *mut whisper_rs_sys::whisper_context,
+    ptr: *mut whisper_rs_sys::whisper_state,
+    i_segment: i32,
+) -> Vec<Word> {
+    let token_eot = whisper_rs_sys::whisper_token_eot(ctx);
+    let mut words = Vec::new();
+    // UTF-8の1文字が複数トークンに分かれることがあるので、文字として揃うまでバイト列をためる
+    let mut pending: Vec<u8> = Vec::new();
+    let mut t0 = 0;
+    let mut t1 = 0;
+    let mut probability = 1.;
+    for i_token in 0..whisper_rs_sys::whisper_full_n_tokens_from_state(ptr, i_segment) {
+        let token = whisper_rs_sys::whisper_full_get_token_data_from_state(ptr, i_segment, i_token);
+        if token.id >= token_eot {
+            continue;
+        }
+        let c_str_ptr =
+            whisper_rs_sys::whisper_full_get_token_text_from_state(ctx, ptr, i_segment, i_token);
+        if c_str_ptr.is_null() {
+            continue;
+        }
+        if pending.is_empty() {
+            t0 = token.t0 * 10;
+            probability = token.p;
+        } else {
+            probability = probability.min(token.p);
+        }
+        t1 = token.t1 * 10;
+        pending.extend_from_slice(CStr::from_ptr(c_str_ptr).to_bytes());
+        if std::str::from_utf8(&pending).is_err_and(|err| err.error_len().is_none()) {
+            continue;
+        }
+        words.push(Word {
+            word: String::from_utf8_lossy(&pending).into_owned(),
+            t0,
+            t1,
+            probability,
+        });
+        pending.clear();
+    }
+    if !pending.is_empty() {
+        words.push(Word {
+            word: String::from_utf8_lossy(&pending).into_owned(),
+            t0,
+            t1,
+            probability,
+        });
+    }
+    words
+}
+
+unsafe extern "C" fn whisper_callback(
+    ctx: *mut whisper_rs_sys::whisper_context,
     ptr: *mut whisper_rs_sys::whisper_state,
     _: i32,
     app: *mut c_void,
 ) {
+    // CallbackDataはtranscribeが持っているので、ここでは借りるだけにする
+    let data = &*(app as *const CallbackData);
+    if data.cancel.load(Ordering::SeqCst) {
+        return;
+    }
     let i_segment = whisper_rs_sys::whisper_full_n_segments_from_state(ptr) - 1;
+    let n_tokens = whisper_rs_sys::whisper_full_n_tokens_from_state(ptr, i_segment);
+    data.tokens
+        .fetch_add(n_tokens.max(0) as usize, Ordering::SeqCst);
+    if data.metrics {
+        emit_metrics(
+            data,
+            whisper_rs_sys::whisper_full_get_segment_t1_from_state(ptr, i_segment) * 10,
+        );
+    }
+    // 無音らしいセグメントはDropなら字幕にせず、Markなら印を付けて残す。Offではすべて残す
+    let no_speech_prob =
+        whisper_rs_sys::whisper_full_get_segment_no_speech_prob_from_state(ptr, i_segment);
+    let no_speech = no_speech_prob > data.no_speech_thold;
+    if no_speech && data.repeat_filter == RepeatFilter::Drop {
+        data.dropped.fetch_add(1, Ordering::SeqCst);
+        let _ = data.app.emit_all(
+            "whisper",
+            WhisperSkippedPayload {
+                status: "skipped".to_string(),
+                code: "segment_skipped".to_string(),
+                params: serde_json::json!({
+                    "segment": i_segment,
+                    "no_speech_prob": no_speech_prob,
+                }),
+                segment: i_segment,
+                no_speech_prob,
+            },
+        );
+        return;
+    }
     let c_str_ptr = whisper_rs_sys::whisper_full_get_segment_text_from_state(ptr, i_segment);
     if c_str_ptr.is_null() {
         return;
     }
-    let c_str = CStr::from_ptr(c_str_ptr);
-    let subtitle = match c_str.to_str() {
+    let words = if data.word_timestamps || data.max_segment_chars.is_some() {
+        segment_words(ctx, ptr, i_segment)
+    } else {
+        Vec::new()
+    };
+    let segment = RawSegment {
+        text: CStr::from_ptr(c_str_ptr).to_bytes(),
+        t0_ms: whisper_rs_sys::whisper_full_get_segment_t0_from_state(ptr, i_segment) * 10,
+        t1_ms: whisper_rs_sys::whisper_full_get_segment_t1_from_state(ptr, i_segment) * 10,
+        no_speech,
+        words,
+        confidence: segment_confidence(ctx, ptr, i_segment),
+        speaker_turn: whisper_rs_sys::whisper_full_get_segment_speaker_turn_next_from_state(
+            ptr, i_segment,
+        ),
+    };
+    deliver_segment(data, segment);
+}
+
+// whisperの状態から読み出した1つのセグメント。ここから先はFFIを使わずに扱う
+struct RawSegment<'a> {
+    text: &'a [u8],
+    t0_ms: i64,
+    t1_ms: i64,
+    no_speech: bool,
+    words: Vec<Word>,
+    confidence: f32,
+    speaker_turn: bool,
+}
+
+// whisper_callbackから見た送り先。テストではAppHandleの代わりに送った内容をためるものを使う
+trait SegmentSink {
+    fn send<S: serde::Serialize + Clone>(&self, event: &str, payload: S);
+    // 範囲の文字起こしでないときは、セグメントをStoreに入れる
+    fn push(&self, segment: Data);
+}
+
+impl SegmentSink for tauri::AppHandle {
+    fn send<S: serde::Serialize + Clone>(&self, event: &str, payload: S) {
+        let _ = self.emit_all(event, payload);
+    }
+
+    fn push(&self, segment: Data) {
+        STORE.lock().unwrap().push(self, segment);
+    }
+}
+
+fn deliver_segment<A: SegmentSink>(data: &CallbackData<A>, segment: RawSegment) {
+    let subtitle = match std::str::from_utf8(segment.text) {
         Ok(str) => str.to_owned(),
         Err(_) => {
-            let app_handle = Box::from_raw(app as *mut tauri::AppHandle);
-            let message = "Text segment could not be converted to string.".to_string();
-            emit_err(&app_handle, &message);
+            // セグメント1つを飛ばすだけなので、実行は止めずに警告として送る
+            let err = WhisperError::SegmentText;
+            data.app.send(
+                "whisper",
+                whisper_payload("warning", err.code(), None, Some(data.run_id)),
+            );
             return;
         }
     };
 
-    let app_handle = Box::from_raw(app as *mut tauri::AppHandle);
-    STORE.lock().unwrap().push_data(
-        &app_handle,
-        whisper_rs_sys::whisper_full_get_segment_t0_from_state(ptr, i_segment) * 10,
-        whisper_rs_sys::whisper_full_get_segment_t1_from_state(ptr, i_segment) * 10,
-        subtitle,
+    let ms_end = segment.t1_ms;
+    if ms_end <= data.skip_before_ms {
+        return;
+    }
+    let ms_start = segment.t0_ms;
+    // 前の区間と重なる部分から始まるセグメントは、前の区間で出した文と重なる分を取り除く
+    let (subtitle, stripped) = if ms_start < data.skip_before_ms {
+        let previous = STORE.lock().unwrap().last_subtitle();
+        match previous.map(|previous| strip_emitted(&previous, &subtitle)) {
+            Some(Some(rest)) => {
+                let stripped = rest != subtitle.trim();
+                (rest, stripped)
+            }
+            Some(None) => return,
+            None => (subtitle, false),
+        }
+    } else {
+        (subtitle, false)
+    };
+    let ms_start = ms_start + data.time_base_ms;
+    let ms_end = ms_end + data.time_base_ms;
+    let words = if stripped {
+        Vec::new()
+    } else {
+        segment
+            .words
+            .into_iter()
+            .map(|word| Word {
+                t0: word.t0 + data.time_base_ms,
+                t1: word.t1 + data.time_base_ms,
+                ..word
+            })
+            .collect()
+    };
+    let confidence = segment.confidence;
+    let repeated = data.repeat_filter != RepeatFilter::Off && {
+        let store = STORE.lock().unwrap();
+        match &data.collected {
+            // 文字起こし直しでは、範囲の中で新しく出たセグメント同士で比べる
+            Some(collected) => store.is_repeated(Some(&collected.lock().unwrap()), &subtitle),
+            None => store.is_repeated(None, &subtitle),
+        }
+    };
+    if repeated && data.repeat_filter == RepeatFilter::Drop {
+        data.dropped.fetch_add(1, Ordering::SeqCst);
+        return;
+    }
+    let suspect = data.repeat_filter == RepeatFilter::Mark && (segment.no_speech || repeated);
+    if suspect {
+        data.marked.fetch_add(1, Ordering::SeqCst);
+    }
+    let speaker_turn = segment.speaker_turn;
+    let pieces = match data.max_segment_chars {
+        Some(max_chars) => split_segment(&subtitle, ms_start, ms_end, max_chars, &words),
+        None => vec![(ms_start, ms_end, subtitle)],
+    };
+    let n_pieces = pieces.len();
+    data.segments.fetch_add(n_pieces, Ordering::SeqCst);
+    for (i, (start_ms, end_ms, message)) in pieces.into_iter().enumerate() {
+        let words: Vec<Word> = words
+            .iter()
+            .filter(|_| data.word_timestamps)
+            .filter(|w| (i == 0 || w.t0 >= start_ms) && (i + 1 == n_pieces || w.t0 < end_ms))
+            .cloned()
+            .collect();
+        let segment = Data::new(
+            start_ms,
+            end_ms,
+            message.clone(),
+            words.clone(),
+            confidence,
+            speaker_turn && i + 1 == n_pieces,
+            suspect,
+        );
+        match &data.collected {
+            Some(collected) => collected.lock().unwrap().push(segment),
+            None => data.app.push(segment),
+        }
+        data.app.send(
+            "whisper",
+            WhisperSegmentPayload {
+                status: "segment".to_string(),
+                code: "segment".to_string(),
+                params: serde_json::json!({ "start_ms": start_ms, "end_ms": end_ms }),
+                run_id: data.run_id,
+                start_ms,
+                end_ms,
+                message,
+                confidence,
+                suspect,
+                words: if data.word_timestamps {
+                    Some(words)
+                } else {
+                    None
+                },
+            },
+        );
+    }
+    if speaker_turn {
+        data.app.send(
+            "whisper",
+            WhisperSpeakerTurnPayload {
+                status: "speaker_turn".to_string(),
+                code: "speaker_turn".to_string(),
+                params: serde_json::json!({ "end_ms": ms_end }),
+                end_ms: ms_end,
+            },
+        );
+    }
+}
+
+// METRICS_INTERVAL_MSおきに、ここまでに処理したトークン数と音声の長さを送る
+fn emit_metrics(data: &CallbackData, audio_ms: i64) {
+    let elapsed_ms = data.started.elapsed().as_millis() as u64;
+    if elapsed_ms - data.last_metrics_ms.load(Ordering::SeqCst) < METRICS_INTERVAL_MS {
+        return;
+    }
+    data.last_metrics_ms.store(elapsed_ms, Ordering::SeqCst);
+    let tokens = data.tokens.load(Ordering::SeqCst);
+    let elapsed_sec = elapsed_ms.max(1) as f32 / 1000.;
+    let _ = data.app.emit_all(
+        "whisper-metrics",
+        WhisperMetricsPayload {
+            run_id: data.run_id,
+            tokens,
+            audio_ms,
+            elapsed_ms,
+            tokens_per_sec: tokens as f32 / elapsed_sec,
+            realtime_factor: if audio_ms > 0 {
+                elapsed_ms as f32 / audio_ms as f32
+            } else {
+                0.
+            },
+        },
     );
-    let _ = Box::into_raw(app_handle);
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     path_wav: &str,
     path_model: &str,
@@ -52,83 +671,1169 @@ pub async fn run(
     translate: bool,
     offset_ms: i32,
     duration_ms: i32,
+    cancel: Arc<AtomicBool>,
+    app: &tauri::AppHandle,
+) -> Result<(), WhisperError> {
+    // デコードもstate.fullも止まったまま戻らないので、asyncのランタイムを塞がないよう別スレッドで行う
+    let run_id = next_run_id();
+    let result = {
+        let app = app.clone();
+        let path_model = PathBuf::from(path_model);
+        tauri::async_runtime::spawn_blocking(move || {
+            // モデルが指定されたときは設定に入れてから始める。空ならStoreの設定をそのまま使う
+            if !path_model.as_os_str().is_empty() {
+                let changed = STORE
+                    .lock()
+                    .map_err(|_| WhisperError::Poisoned)?
+                    .set_path_model(&app, path_model)
+                    .map_err(|err| WhisperError::Config {
+                        message: err.to_string(),
+                    })?;
+                if changed {
+                    MODEL_CACHE.invalidate();
+                }
+            }
+            run_blocking(run_id, cancel, &app)
+        })
+        .await
+        .unwrap_or(Err(WhisperError::Thread))
+    };
+    // ファイル全体を文字起こししたときだけつなげる。範囲の文字起こしし直しはsplice_rangeの中でつなげる
+    if result.is_ok() {
+        if let Ok(mut store) = STORE.lock() {
+            if let Some(max_gap_ms) = store.get_gap_merge_ms() {
+                store.merge_by_gap(app, max_gap_ms);
+            }
+        }
+    }
+    finish_run(app, run_id, result)
+}
+
+fn run_blocking(
+    run_id: u64,
+    cancel: Arc<AtomicBool>,
+    app: &tauri::AppHandle,
+) -> Result<RunStats, WhisperError> {
+    cancel.store(false, Ordering::SeqCst);
+    let audio_data = load_audio(run_id, &cancel, app)?;
+    let (vad, ms_offset, ms_duration) = {
+        let config = STORE.lock().map_err(|_| WhisperError::Poisoned)?;
+        (
+            config.get_vad(),
+            config.get_ms_offset(),
+            config.get_ms_duration(),
+        )
+    };
+    match vad {
+        Some(min_silence_ms) => transcribe_speech(
+            &audio_data,
+            ms_offset,
+            ms_duration,
+            min_silence_ms,
+            run_id,
+            cancel,
+            app,
+        ),
+        None => transcribe(&audio_data, None, None, None, run_id, cancel, app),
+    }
+}
+
+// 設定の音声を16kHzモノラルにして返す
+fn load_audio(
+    run_id: u64,
+    cancel: &AtomicBool,
+    app: &tauri::AppHandle,
+) -> Result<Vec<f32>, WhisperError> {
+    AudioSource::from_store()?.decode(run_id, cancel, || {
+        emit_status(app, "resampling", "resampling")
+    })
+}
+
+// デコードとリサンプリングは時間がかかるので、必要な設定だけ写してStoreのロックを外してから行う
+struct AudioSource {
+    path_wav: PathBuf,
+    channel: Option<usize>,
+    audio_track: usize,
+}
+
+impl AudioSource {
+    fn from_store() -> Result<AudioSource, WhisperError> {
+        let config = STORE.lock().map_err(|_| WhisperError::Poisoned)?;
+        config
+            .validate_config()
+            .map_err(|errors| WhisperError::Config {
+                message: config_error_message(&errors),
+            })?;
+        Ok(AudioSource {
+            path_wav: config.get_path_wav().to_path_buf(),
+            channel: config.get_channel(),
+            audio_track: config.get_audio_track(),
+        })
+    }
+
+    // リサンプリングが要るときはon_resampleを呼んでから変換する。
+    // 一時WAVの名前に実行の番号を入れて、同時に動く実行（一括処理とキューなど）と取り合わないようにする
+    fn decode(
+        &self,
+        run_id: u64,
+        cancel: &AtomicBool,
+        on_resample: impl FnOnce(),
+    ) -> Result<Vec<f32>, WhisperError> {
+        // 映像から取り出した一時WAVはデコードし終えてこの関数を抜けるときに削除される
+        let mut temp_wav: Option<audio::TempFile> = None;
+        if audio::is_video_container(&self.path_wav) {
+            let temp = temp_wav.insert(audio::TempFile::new(std::env::temp_dir().join(format!(
+                "pothook-{}-{}.wav",
+                std::process::id(),
+                run_id
+            ))));
+            audio::extract_audio_track(&self.path_wav, temp.path(), self.audio_track)?;
+        }
+        let path_input = temp_wav
+            .as_ref()
+            .map_or(self.path_wav.as_path(), |temp| temp.path());
+        if !audio::is_whisper_wav(path_input) {
+            on_resample();
+        }
+        let audio_data = audio::decode_to_f32_mono_16k(path_input, self.channel, self.audio_track)?;
+        if cancel.load(Ordering::SeqCst) {
+            return Err(WhisperError::Cancelled);
+        }
+        Ok(audio_data)
+    }
+}
+
+// start_ms〜end_msだけを文字起こしし直し、その範囲に重なるセグメントと1回で入れ替える。
+// path_modelを指定したときはこの範囲だけそのモデルを使う
+pub async fn run_range(
+    start_ms: i64,
+    end_ms: i64,
+    path_model: Option<PathBuf>,
+    cancel: Arc<AtomicBool>,
+    app: &tauri::AppHandle,
+) -> Result<(), WhisperError> {
+    let run_id = next_run_id();
+    let result = {
+        let app = app.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            run_range_blocking(start_ms, end_ms, path_model, run_id, cancel, &app)
+        })
+        .await
+        .unwrap_or(Err(WhisperError::Thread))
+    };
+    finish_run(app, run_id, result)
+}
+
+fn run_range_blocking(
+    start_ms: i64,
+    end_ms: i64,
+    path_model: Option<PathBuf>,
+    run_id: u64,
+    cancel: Arc<AtomicBool>,
+    app: &tauri::AppHandle,
+) -> Result<RunStats, WhisperError> {
+    cancel.store(false, Ordering::SeqCst);
+    if start_ms < 0 || start_ms >= end_ms {
+        return Err(WhisperError::Config {
+            message: format!("文字起こしし直す範囲が不正です: {}〜{}ms", start_ms, end_ms),
+        });
+    }
+    let audio_data = load_audio(run_id, &cancel, app)?;
+    let samples_per_ms = audio::WHISPER_SAMPLE_RATE as usize / 1000;
+    let start = (start_ms as usize * samples_per_ms).min(audio_data.len());
+    let end = (end_ms as usize * samples_per_ms).min(audio_data.len());
+    if start >= end {
+        return Err(WhisperError::Config {
+            message: format!(
+                "文字起こしし直す範囲が音声の外です: {}〜{}ms",
+                start_ms, end_ms
+            ),
+        });
+    }
+    // 文字起こし直しは結果を1回の変更として記録するので、rangeを渡してそれまでの履歴は消さない
+    let window = StreamWindow {
+        time_base_ms: start_ms,
+        skip_before_ms: 0,
+        first: true,
+        run_duration_ms: None,
+    };
+    let range = RangeRun {
+        path_model,
+        collected: Arc::new(Mutex::new(Vec::new())),
+    };
+    // 途中で失敗したときは何も入れ替えず、元のセグメントのまま残す
+    let stats = transcribe(
+        &audio_data[start..end],
+        Some(window),
+        Some(&range),
+        None,
+        run_id,
+        cancel,
+        app,
+    )?;
+    let segments =
+        std::mem::take(&mut *range.collected.lock().map_err(|_| WhisperError::Poisoned)?);
+    STORE
+        .lock()
+        .map_err(|_| WhisperError::Poisoned)?
+        .splice_range(app, start_ms, end_ms, segments);
+    Ok(stats)
+}
+
+// 発話のある区間ごとに文字起こしする。区間の先頭の時刻を足して元の音声の時刻に戻す
+fn transcribe_speech(
+    audio_data: &[f32],
+    ms_offset: i32,
+    ms_duration: i32,
+    min_silence_ms: usize,
+    run_id: u64,
+    cancel: Arc<AtomicBool>,
+    app: &tauri::AppHandle,
+) -> Result<RunStats, WhisperError> {
+    let samples_per_ms = audio::WHISPER_SAMPLE_RATE as usize / 1000;
+    let start = (ms_offset.max(0) as usize * samples_per_ms).min(audio_data.len());
+    let end = match ms_duration {
+        ms if ms > 0 => (start + ms as usize * samples_per_ms).min(audio_data.len()),
+        _ => audio_data.len(),
+    };
+    let regions = audio::speech_regions(&audio_data[start..end], min_silence_ms);
+    let speech_ms: i64 = regions
+        .iter()
+        .map(|region| audio::duration_ms(&audio_data[region.clone()]))
+        .sum();
+    let _ = app.emit_all(
+        "whisper",
+        WhisperVadPayload {
+            status: "vad".to_string(),
+            code: "vad_regions".to_string(),
+            params: serde_json::json!({
+                "regions": regions.len(),
+                "speech_ms": speech_ms,
+            }),
+            regions: regions.len(),
+            speech_ms,
+            skipped_ms: audio::duration_ms(&audio_data[start..end]) - speech_ms,
+        },
+    );
+    // 飛ばした無音も含めた範囲の長さを文字起こしした音声の長さとする
+    let mut total = RunStats {
+        audio_duration_ms: audio::duration_ms(&audio_data[start..end]).max(0) as u64,
+        ..Default::default()
+    };
+    // 言語は最初の区間で判定したものを残りの区間でも使う
+    for (i, region) in regions.into_iter().enumerate() {
+        let window = StreamWindow {
+            time_base_ms: ((start + region.start) / samples_per_ms) as i64,
+            skip_before_ms: 0,
+            first: i == 0,
+            run_duration_ms: Some(total.audio_duration_ms as i32),
+        };
+        let lang = total.lang.clone();
+        total.add(transcribe(
+            &audio_data[start + region.start..start + region.end],
+            Some(window),
+            None,
+            lang.as_deref(),
+            run_id,
+            cancel.clone(),
+            app,
+        )?);
+    }
+    Ok(total)
+}
+
+// マイク入力など、すでに16kHzモノラルになっている音声を直接文字起こしする
+pub async fn run_samples(
+    audio_data: Vec<f32>,
+    cancel: Arc<AtomicBool>,
+    app: &tauri::AppHandle,
+) -> Result<(), WhisperError> {
+    cancel.store(false, Ordering::SeqCst);
+    let run_id = next_run_id();
+    let result = {
+        let app = app.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            transcribe(&audio_data, None, None, None, run_id, cancel, &app)
+        })
+        .await
+        .unwrap_or(Err(WhisperError::Thread))
+    };
+    finish_run(app, run_id, result)
+}
+
+// 受け取った音声を10秒ずつ、5秒ずらしながら文字起こしする。
+// 前半5秒で終わるセグメントは前の区間で出力済みなので捨てる
+pub fn run_stream(audio_rx: Receiver<Vec<f32>>, app: tauri::AppHandle) -> Result<(), WhisperError> {
+    let run_id = next_run_id();
+    let result = transcribe_stream(audio_rx, run_id, &app);
+    finish_run(&app, run_id, result)
+}
+
+fn transcribe_stream(
+    audio_rx: Receiver<Vec<f32>>,
+    run_id: u64,
+    app: &tauri::AppHandle,
+) -> Result<RunStats, WhisperError> {
+    let cancel = CANCEL_FLAG.clone();
+    cancel.store(false, Ordering::SeqCst);
+    stream_windows(audio_rx, &cancel, |audio_data, window, lang| {
+        transcribe(
+            audio_data,
+            Some(window),
+            None,
+            lang,
+            run_id,
+            cancel.clone(),
+            app,
+        )
+    })
+}
+
+// 受け取った音声を区間に分けて、区間ごとにtranscribe_windowを呼ぶ
+fn stream_windows(
+    audio_rx: Receiver<Vec<f32>>,
+    cancel: &AtomicBool,
+    mut transcribe_window: impl FnMut(
+        &[f32],
+        StreamWindow,
+        Option<&str>,
+    ) -> Result<RunStats, WhisperError>,
+) -> Result<RunStats, WhisperError> {
+    const STREAM_WINDOW_MS: i64 = 10_000;
+    const STREAM_OVERLAP_MS: i64 = 5_000;
+    let samples_per_ms = audio::WHISPER_SAMPLE_RATE as usize / 1000;
+    let window_len = STREAM_WINDOW_MS as usize * samples_per_ms;
+    let overlap_len = STREAM_OVERLAP_MS as usize * samples_per_ms;
+    let mut buffer: Vec<f32> = Vec::new();
+    let mut time_base_ms = 0;
+    let mut skip_before_ms = 0;
+    let mut closed = false;
+    let mut received = 0;
+    let mut total = RunStats::default();
+    while !closed {
+        match audio_rx.recv() {
+            Ok(chunk) => {
+                received += chunk.len();
+                buffer.extend(chunk);
+            }
+            Err(_) => closed = true,
+        }
+        if cancel.load(Ordering::SeqCst) {
+            return Err(WhisperError::Cancelled);
+        }
+        // 受信が終わったら、重なり部分より長く残っている分だけ最後に文字起こしする
+        while buffer.len() >= window_len || (closed && buffer.len() > overlap_len) {
+            let end = buffer.len().min(window_len);
+            let window = StreamWindow {
+                time_base_ms,
+                skip_before_ms,
+                first: time_base_ms == 0,
+                run_duration_ms: None,
+            };
+            let lang = total.lang.clone();
+            total.add(transcribe_window(&buffer[..end], window, lang.as_deref())?);
+            let keep_from = end.saturating_sub(overlap_len);
+            buffer.drain(..keep_from);
+            time_base_ms += (keep_from / samples_per_ms) as i64;
+            skip_before_ms = STREAM_OVERLAP_MS;
+        }
+    }
+    // 区間が重なっているので、受け取った音声の長さを文字起こしした長さとする
+    total.audio_duration_ms = (received / samples_per_ms) as u64;
+    Ok(total)
+}
+
+// langは同じ実行の前の区間で決めた言語。あれば判定し直さない
+fn transcribe(
+    audio_data: &[f32],
+    window: Option<StreamWindow>,
+    range: Option<&RangeRun>,
+    lang: Option<&str>,
+    run_id: u64,
+    cancel: Arc<AtomicBool>,
     app: &tauri::AppHandle,
-) -> Result<(), String> {
-    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
-    let audio_data;
+) -> Result<RunStats, WhisperError> {
+    let _running = MODEL_CACHE.lock_run().map_err(|_| WhisperError::Poisoned)?;
+    let first = window.is_none_or(|window| window.first);
+    if first && range.is_none() {
+        STORE
+            .lock()
+            .map_err(|_| WhisperError::Poisoned)?
+            .clear_history();
+    }
+    // paramsが借用するのでparamsより先に宣言しておく
+    let prompt_tokens: Vec<c_int>;
+    let sampling_strategy = STORE
+        .lock()
+        .map_err(|_| WhisperError::Poisoned)?
+        .get_sampling_strategy();
+    let mut params = FullParams::new(to_sampling_strategy(sampling_strategy));
     let lang_string;
-    let context;
+    let initial_prompt;
+    let path_model;
+    let gpu;
+    let ms_offset;
+    let ms_duration;
+    let progress_interval;
+    let n_threads;
+    let fallback;
+    let word_timestamps;
+    let max_segment_chars;
+    let suppress_tokens: Vec<i32>;
+    let auto_lang;
+    let lang_detect_min_prob;
+    let fallback_lang;
+    let repeat_filter;
+    let metrics;
     {
-        let mut config = STORE.lock().map_err(|_| "Mutex is poisoned")?;
+        let config = STORE.lock().map_err(|_| WhisperError::Poisoned)?;
         // Storeの設定を更新する処理
         // ...
 
-        let reader_result = hound::WavReader::open(config.get_path_wav());
-        if reader_result.is_err() {
-            emit_err(app, "指定されたwavファイルを開けませんでした");
-            return Err("指定されたwavファイルを開けませんでした".to_string());
-        }
-        let mut reader = reader_result.unwrap();
-        audio_data = reader
-            .samples::<i16>()
-            .map(|sample| sample.map(|s| s as f32 / i16::MAX as f32))
-            .collect::<Result<Vec<f32>, _>>()
-            .map_err(|_| "Failed to read samples from WAV file".to_string())?;
-
         lang_string = config.get_lang().unwrap_or("ja").to_string();
-        params.set_language(Some(&lang_string));
+        auto_lang = config.is_auto_lang();
+        lang_detect_min_prob = config.get_lang_detect_min_prob();
+        fallback_lang = config.get_fallback_lang().map(String::from);
+        repeat_filter = config.get_repeat_filter();
+        metrics = config.get_metrics();
+        // 自動判定のときはStateを作ってから言語判定する
+        match lang {
+            Some(lang) => params.set_language(Some(lang)),
+            None if auto_lang => params.set_language(None),
+            None => params.set_language(Some(&lang_string)),
+        }
         params.set_translate(config.get_translate());
-        params.set_offset_ms(config.get_ms_offset());
-        params.set_duration_ms(config.get_ms_duration());
+        initial_prompt = config.get_initial_prompt().map(String::from);
+        // 文字起こし直す範囲やライブ音声の区間は渡された音声をすべて使う
+        ms_offset = match window {
+            Some(_) => 0,
+            None => config.get_ms_offset(),
+        };
+        // 0は最後までを表すので、whisperには実際の長さを渡す
+        ms_duration = match config.get_ms_duration() {
+            ms if ms > 0 && window.is_none() => ms,
+            _ => (audio::duration_ms(audio_data) as i32 - ms_offset).max(0),
+        };
+        fallback = config.get_fallback_settings();
+        params.set_temperature(fallback.temperature);
+        params.set_temperature_inc(fallback.temperature_inc);
+        params.set_entropy_thold(fallback.entropy_thold);
+        params.set_logprob_thold(fallback.logprob_thold);
+        params.set_no_speech_thold(fallback.no_speech_thold);
+        progress_interval = config.get_progress_interval();
+        n_threads = config.get_n_threads();
+        params.set_n_threads(n_threads);
+        params.set_offset_ms(ms_offset);
+        params.set_duration_ms(ms_duration);
+        params.set_n_max_text_ctx(config.get_max_context_tokens());
+        params.set_audio_ctx(config.get_audio_ctx(ms_duration) as c_int);
         params.set_tdrz_enable(true);
-        params.set_suppress_non_speech_tokens(true);
-        
-        // コールバックとユーザーデータの設定
-        unsafe {
-            params.set_new_segment_callback(Some(whisper_callback));
-            params.set_new_segment_callback_user_data(
-                Box::into_raw(Box::new(app.clone())) as *mut c_void
-            );
+        word_timestamps = config.get_word_timestamps();
+        max_segment_chars = config.get_max_segment_chars();
+        // max_lenはトークンのタイムスタンプがないと効かない。分け直すときも単語の時刻を使う
+        let max_len = config.get_max_len();
+        if word_timestamps || max_len > 0 || max_segment_chars.is_some() {
+            params.set_token_timestamps(true);
+        }
+        if max_len > 0 {
+            params.set_max_len(max_len);
+            params.set_split_on_word(config.get_split_on_word());
         }
+        params.set_suppress_blank(config.get_suppress_blank());
+        // -1はwhisperの既定の抑制リスト（非音声トークン）を表す
+        suppress_tokens = config.get_suppress_tokens().to_vec();
+        params.set_suppress_non_speech_tokens(suppress_tokens.contains(&-1));
+        path_model = range
+            .and_then(|range| range.path_model.clone())
+            .unwrap_or_else(|| config.get_path_model().to_path_buf());
+        gpu = config.get_gpu_settings();
+    }
+    // モデルの読み込みには数十秒かかることがあるので、Storeのロックを外してから行う
+    let loaded = models::validate(&path_model, false)
+        .map_err(|err| err.to_string())
+        .and_then(|_| MODEL_CACHE.get(&path_model, gpu))
+        .map_err(|message| WhisperError::ModelLoad {
+            path: path_model.clone(),
+            message,
+        })?;
+    if loaded.gpu_fallback && first {
+        emit_status(app, "warning", "gpu_fallback");
+    }
+    let context = loaded.context;
+    let mut lang_used = match lang {
+        Some(lang) => Some(lang.to_string()),
+        None => (!auto_lang).then(|| lang_string.clone()),
+    };
+    // whisperが使えるプロンプトは224トークンまでなので、超えた分は先頭から捨てて末尾を残す
+    if let Some(prompt) = &initial_prompt {
+        match context.tokenize(prompt, prompt.len() + 1) {
+            Ok(mut tokens) => {
+                if tokens.len() > MAX_PROMPT_TOKENS {
+                    tokens.drain(..tokens.len() - MAX_PROMPT_TOKENS);
+                    if first {
+                        emit_status_with(
+                            app,
+                            "warning",
+                            "prompt_truncated",
+                            serde_json::json!({ "max_tokens": MAX_PROMPT_TOKENS }),
+                        );
+                    }
+                }
+                prompt_tokens = tokens;
+                params.set_tokens(&prompt_tokens);
+            }
+            Err(_) => params.set_initial_prompt(prompt),
+        }
+    }
+    if cancel.load(Ordering::SeqCst) {
+        return Err(WhisperError::Cancelled);
+    }
 
-        context = WhisperContext::new_with_params(
-            config.get_path_model().to_str().unwrap(),
-            WhisperContextParameters::default(),
-        )
-        .map_err(|_| "言語モデルの読み込みに失敗しました".to_string())?;
+    // コールバックとユーザーデータの設定。
+    // CallbackDataはこの関数の終わりまで持っておき、どこで戻っても一度だけ解放する
+    let callback_data = Arc::new(CallbackData {
+        app: app.clone(),
+        run_id,
+        cancel: cancel.clone(),
+        progress_interval,
+        started: Instant::now(),
+        last_progress: AtomicI32::new(-1),
+        no_speech_thold: fallback.no_speech_thold,
+        word_timestamps,
+        max_segment_chars,
+        suppress_tokens: suppress_tokens.into_iter().filter(|&id| id >= 0).collect(),
+        time_base_ms: window.map_or(0, |window| window.time_base_ms),
+        skip_before_ms: window.map_or(0, |window| window.skip_before_ms),
+        collected: range.map(|range| range.collected.clone()),
+        repeat_filter,
+        dropped: AtomicUsize::new(0),
+        marked: AtomicUsize::new(0),
+        segments: AtomicUsize::new(0),
+        metrics,
+        tokens: AtomicUsize::new(0),
+        last_metrics_ms: AtomicU64::new(0),
+    });
+    // コールバックはstate.fullの中からしか呼ばれず、stateはcallback_dataより後に宣言しているので先に破棄される。
+    // そのためコールバックは借りるだけでよく、解放はcallback_dataが落ちるときの1回だけになる
+    let user_data = Arc::as_ptr(&callback_data) as *mut c_void;
+    unsafe {
+        params.set_new_segment_callback(Some(whisper_callback));
+        params.set_new_segment_callback_user_data(user_data);
+        params.set_start_encoder_callback(Some(encoder_begin_callback));
+        params.set_start_encoder_callback_user_data(user_data);
+        params.set_progress_callback(Some(progress_callback));
+        params.set_progress_callback_user_data(user_data);
+        params.set_filter_logits_callback(Some(logits_filter_callback));
+        params.set_filter_logits_callback_user_data(user_data);
     }
 
     // エラーハンドリングを伴うStateの作成
-    let mut state = context.create_state().map_err(|_| {
-        emit_err(app, "Whisper Stateの初期化に失敗しました");
-        "Whisper Stateの初期化に失敗しました".to_string()
-    })?;
-    
+    let mut state = context
+        .create_state()
+        .map_err(|_| WhisperError::StateInit)?;
+
+    if auto_lang && lang.is_none() {
+        let (lang, probability) = detect_language(&mut state, audio_data, ms_offset, n_threads)
+            .map_err(|message| WhisperError::LanguageDetect { message })?;
+        let low_confidence = probability < lang_detect_min_prob;
+        let _ = app.emit_all(
+            "whisper",
+            WhisperLanguagePayload {
+                status: "language-detected".to_string(),
+                code: "language_detected".to_string(),
+                params: serde_json::json!({ "lang": lang, "probability": probability }),
+                lang: lang.to_string(),
+                probability,
+                low_confidence,
+            },
+        );
+        // 短い音声では判定を誤りやすいので、確率が低いときは既定の言語にするか利用者に選んでもらう
+        let lang = match (&fallback_lang, low_confidence) {
+            (_, false) => lang,
+            (Some(fallback_lang), true) => fallback_lang.as_str(),
+            (None, true) => {
+                let _ = app.emit_all(
+                    "whisper",
+                    WhisperLanguagePayload {
+                        status: "lang_uncertain".to_string(),
+                        code: "language_uncertain".to_string(),
+                        params: serde_json::json!({ "lang": lang, "probability": probability }),
+                        lang: lang.to_string(),
+                        probability,
+                        low_confidence,
+                    },
+                );
+                return Err(WhisperError::LanguageUncertain);
+            }
+        };
+        params.set_language(Some(lang));
+        lang_used = Some(lang.to_string());
+        STORE
+            .lock()
+            .map_err(|_| WhisperError::Poisoned)?
+            .set_detected_lang(app, Some(bcp47(lang)));
+    }
+
     // 開始イベントを送信
-    if let Err(_) = app.emit_all(
-        "whisper",
-        WhisperPayload {
-            status: "start".to_string(),
-            message: "初期化が完了しました。文字起こしを開始します。".to_string(),
-        },
-    ) {
-        return Err("イベントの送信に失敗しました".to_string());
+    if first
+        && app
+            .emit_all(
+                "whisper",
+                WhisperStartPayload {
+                    status: "start".to_string(),
+                    run_id,
+                    code: "transcription_started".to_string(),
+                    message: messages::text("transcription_started", &serde_json::Value::Null),
+                    n_threads,
+                    fallback,
+                    duration_ms: window
+                        .and_then(|window| window.run_duration_ms)
+                        .unwrap_or(ms_duration),
+                    backend: loaded.backend,
+                },
+            )
+            .is_err()
+    {
+        return Err(WhisperError::EventEmit);
     }
 
     // 文字起こし処理の実行
-    state.full(params, &audio_data[..]).map_err(|_| {
-        emit_err(app, "言語モデルの実行に失敗しました");
-        "言語モデルの実行に失敗しました".to_string()
-    })?;
-    Ok(())
+    let started = Instant::now();
+    let result = state.full(params, audio_data);
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    if cancel.load(Ordering::SeqCst) {
+        return Err(WhisperError::Cancelled);
+    }
+    result.map_err(|_| WhisperError::Inference)?;
+    Ok(RunStats {
+        segment_count: callback_data.segments.load(Ordering::SeqCst),
+        dropped: callback_data.dropped.load(Ordering::SeqCst),
+        marked: callback_data.marked.load(Ordering::SeqCst),
+        audio_duration_ms: ms_duration.max(0) as u64,
+        elapsed_ms,
+        tokens: callback_data.tokens.load(Ordering::SeqCst),
+        tokens_per_sec: 0.,
+        realtime_factor: 0.,
+        model_path: path_model,
+        lang: lang_used,
+    })
 }
 
-fn emit_err(app: &tauri::AppHandle, msg: &str) {
+pub fn to_sampling_strategy(config: SamplingStrategyConfig) -> SamplingStrategy {
+    match config {
+        SamplingStrategyConfig::Greedy { best_of } => SamplingStrategy::Greedy {
+            best_of: best_of as i32,
+        },
+        SamplingStrategyConfig::BeamSearch {
+            beam_size,
+            patience,
+        } => SamplingStrategy::BeamSearch {
+            beam_size: beam_size as i32,
+            patience,
+        },
+    }
+}
+
+// 前の区間で出した文の末尾と重なる先頭部分を取り除く。すべて出力済みならNoneを返す
+fn strip_emitted(previous: &str, text: &str) -> Option<String> {
+    const MIN_OVERLAP_CHARS: usize = 2;
+    let text = text.trim();
+    if text.is_empty() || previous.contains(text) {
+        return None;
+    }
+    let previous = previous.trim_end();
+    let chars: Vec<char> = text.chars().collect();
+    let overlap = (MIN_OVERLAP_CHARS..chars.len())
+        .rev()
+        .find(|&n| previous.ends_with(&chars[..n].iter().collect::<String>()));
+    Some(match overlap {
+        Some(n) => chars[n..].iter().collect::<String>().trim().to_string(),
+        None => text.to_string(),
+    })
+}
+
+fn config_error_message(errors: &[ConfigError]) -> String {
+    errors
+        .iter()
+        .map(|err| err.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// whisperの言語コードはほぼISO 639-1なのでそのままBCP 47として使えるが、ジャワ語だけ古いコードになっている
+fn bcp47(lang: &str) -> String {
+    match lang {
+        "jw" => "jv".to_string(),
+        lang => lang.to_string(),
+    }
+}
+
+// 長すぎるセグメントを文の区切り、読点、空白の順で探して分ける。
+// 時間は単語のタイムスタンプがあればそこから、なければ文字数の比率で割り振る
+fn split_segment(
+    subtitle: &str,
+    ms_start: i64,
+    ms_end: i64,
+    max_chars: usize,
+    words: &[Word],
+) -> Vec<(i64, i64, String)> {
+    let chars: Vec<char> = subtitle.trim().chars().collect();
+    let total = chars.len();
+    if max_chars == 0 || total <= max_chars {
+        return vec![(ms_start, ms_end, subtitle.to_string())];
+    }
+    // 単語は前後の空白を含めた字幕を分けたものなので、trimした分だけ位置をずらす
+    let leading = subtitle.chars().take_while(|c| c.is_whitespace()).count();
+    let mut word_starts = Vec::new();
+    let mut position: usize = 0;
+    for word in words {
+        word_starts.push((position.saturating_sub(leading), word.t0));
+        position += word.word.chars().count();
+    }
+    let time_at = |i: usize| match word_starts.iter().rev().find(|(start, _)| *start <= i) {
+        Some(&(_, t0)) if i > 0 && i < total => t0.clamp(ms_start, ms_end),
+        _ => ms_start + (ms_end - ms_start) * i as i64 / total as i64,
+    };
+    let is_sentence_end = |i: usize| match chars[i] {
+        '。' | '？' | '！' => true,
+        '.' | '?' | '!' => chars.get(i + 1).is_none_or(|c| c.is_whitespace()),
+        _ => false,
+    };
+    let mut pieces = Vec::new();
+    let mut begin = 0;
+    while begin < total {
+        let end = if total - begin <= max_chars {
+            total
+        } else {
+            let limit = begin + max_chars;
+            (begin..limit)
+                .rev()
+                .find(|&i| is_sentence_end(i))
+                .map(|i| i + 1)
+                .or_else(|| {
+                    (begin..limit)
+                        .rev()
+                        .find(|&i| matches!(chars[i], '、' | '，'))
+                        .map(|i| i + 1)
+                })
+                .or_else(|| {
+                    (begin + 1..=limit)
+                        .rev()
+                        .find(|&i| chars[i].is_whitespace())
+                })
+                .unwrap_or(limit)
+        };
+        let text = chars[begin..end].iter().collect::<String>();
+        if !text.trim().is_empty() {
+            pieces.push((time_at(begin), time_at(end), text.trim().to_string()));
+        }
+        begin = end;
+    }
+    pieces
+}
+
+// 先頭30秒から言語を判定し、言語コードとその確率を返す
+fn detect_language(
+    state: &mut WhisperState,
+    audio_data: &[f32],
+    ms_offset: i32,
+    n_threads: i32,
+) -> Result<(&'static str, f32), String> {
+    let rate = audio::WHISPER_SAMPLE_RATE as usize;
+    let start = (ms_offset.max(0) as usize * rate / 1000).min(audio_data.len());
+    let end = (start + 30 * rate).min(audio_data.len());
+    let threads = n_threads.max(1) as usize;
+    state
+        .pcm_to_mel(&audio_data[start..end], threads)
+        .map_err(|_| "言語判定の準備に失敗しました".to_string())?;
+    let probs = state
+        .lang_detect(0, threads)
+        .map_err(|_| "言語判定に失敗しました".to_string())?;
+    probs
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .and_then(|(id, p)| whisper_rs::get_lang_str(id as i32).map(|lang| (lang, *p)))
+        .ok_or_else(|| "言語を判定できませんでした".to_string())
+}
+
+// whisper.cppが対応している言語の一覧を名前順で返す
+pub fn languages(selected: &str) -> Vec<WhisperLanguage> {
+    let lang_str = |ptr: *const libc::c_char| {
+        if ptr.is_null() {
+            return None;
+        }
+        let c_str = unsafe { CStr::from_ptr(ptr) };
+        c_str.to_str().ok().map(String::from)
+    };
+    let max_id = unsafe { whisper_rs_sys::whisper_lang_max_id() };
+    let mut languages: Vec<WhisperLanguage> = (0..=max_id)
+        .filter_map(|id| {
+            let code = lang_str(unsafe { whisper_rs_sys::whisper_lang_str(id) })?;
+            let name = lang_str(unsafe { whisper_rs_sys::whisper_lang_str_full(id) })?;
+            Some(WhisperLanguage {
+                selected: code == selected,
+                code,
+                name,
+            })
+        })
+        .collect();
+    languages.sort_by(|a, b| a.name.cmp(&b.name));
+    languages
+}
+
+pub fn emit_status(app: &tauri::AppHandle, status: &str, code: &str) {
+    emit_payload(app, status, code, None, None);
+}
+
+// {name}に入れる値をparamsで渡す
+pub fn emit_status_with(
+    app: &tauri::AppHandle,
+    status: &str,
+    code: &str,
+    params: serde_json::Value,
+) {
+    emit_payload(app, status, code, Some(params), None);
+}
+
+fn emit_payload(
+    app: &tauri::AppHandle,
+    status: &str,
+    code: &str,
+    params: Option<serde_json::Value>,
+    run_id: Option<u64>,
+) {
+    let _ = app.emit_all("whisper", whisper_payload(status, code, params, run_id));
+}
+
+fn whisper_payload(
+    status: &str,
+    code: &str,
+    params: Option<serde_json::Value>,
+    run_id: Option<u64>,
+) -> WhisperPayload {
+    WhisperPayload {
+        status: status.to_string(),
+        code: code.to_string(),
+        message: messages::text(code, params.as_ref().unwrap_or(&serde_json::Value::Null)),
+        params,
+        run_id,
+    }
+}
+
+// 複数のウィンドウで表示をそろえられるよう、更新後のセグメントをまるごと送る
+pub fn emit_segment_updated(app: &tauri::AppHandle, segment: SegmentData) {
     let _ = app.emit_all(
         "whisper",
-        WhisperPayload {
-            status: "error".to_string(),
-            message: msg.to_string(),
+        WhisperSegmentUpdatedPayload {
+            status: "segment_updated".to_string(),
+            code: "segment_updated".to_string(),
+            params: serde_json::json!({ "index": segment.index }),
+            segment,
         },
     );
 }
+
+// 1回の実行につき、done・cancelled・errorのどれか1つだけを送る。
+// エラーの中身（パスや元のメッセージ）はそのままparamsとして送る
+fn finish_run(
+    app: &tauri::AppHandle,
+    run_id: u64,
+    result: Result<RunStats, WhisperError>,
+) -> Result<(), WhisperError> {
+    match result {
+        Ok(mut stats) => {
+            // 区間ごとではなく、実行全体で1回だけ送る
+            if stats.dropped > 0 || stats.marked > 0 {
+                let _ = app.emit_all(
+                    "whisper",
+                    WhisperSuppressedPayload {
+                        status: "suppressed".to_string(),
+                        code: "segments_suppressed".to_string(),
+                        params: serde_json::json!({
+                            "dropped": stats.dropped,
+                            "marked": stats.marked,
+                        }),
+                        dropped: stats.dropped,
+                        marked: stats.marked,
+                    },
+                );
+            }
+            if stats.audio_duration_ms > 0 {
+                stats.realtime_factor = stats.elapsed_ms as f32 / stats.audio_duration_ms as f32;
+            }
+            if stats.elapsed_ms > 0 {
+                stats.tokens_per_sec = stats.tokens as f32 * 1000. / stats.elapsed_ms as f32;
+            }
+            let _ = app.emit_all(
+                "whisper",
+                WhisperDonePayload {
+                    status: "done".to_string(),
+                    code: "transcription_done".to_string(),
+                    params: serde_json::json!({
+                        "segment_count": stats.segment_count,
+                        "elapsed_ms": stats.elapsed_ms,
+                    }),
+                    run_id,
+                    stats,
+                },
+            );
+            Ok(())
+        }
+        Err(WhisperError::Cancelled) => {
+            let err = WhisperError::Cancelled;
+            emit_payload(app, "cancelled", err.code(), None, Some(run_id));
+            Err(err)
+        }
+        Err(err) => {
+            let params = serde_json::to_value(&err).unwrap_or_default();
+            emit_payload(app, "error", err.code(), Some(params), Some(run_id));
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    // 実行の準備中にUIのコマンドがStoreを使っていても、デコードはStoreを待たずに進む
+    #[test]
+    fn decode_runs_while_store_is_locked() {
+        let temp = audio::TempFile::new(std::env::temp_dir().join(format!(
+            "pothook-test-{}-concurrent.wav",
+            std::process::id()
+        )));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: audio::WHISPER_SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(temp.path(), spec).unwrap();
+        for i in 0..audio::WHISPER_SAMPLE_RATE * 5 {
+            writer.write_sample((i % 100) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        let source = AudioSource {
+            path_wav: temp.path().to_path_buf(),
+            channel: None,
+            audio_track: 0,
+        };
+        let store = STORE.lock().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let decoding = std::thread::spawn(move || {
+            let _ = tx.send(source.decode(0, &AtomicBool::new(false), || ()));
+        });
+        let decoded = rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("decoding waited for the STORE lock")
+            .unwrap();
+        // デコードの間もStoreは読める
+        assert!(store.get_path_wav().as_os_str().is_empty());
+        drop(store);
+        decoding.join().unwrap();
+        assert_eq!(decoded.len(), audio::WHISPER_SAMPLE_RATE as usize * 5);
+    }
+
+    // ライブ文字起こしでは、次の区間の先頭に前の区間の最後の数秒がもう一度入ってくる
+    #[test]
+    fn strip_emitted_removes_overlap_with_previous_window() {
+        assert_eq!(
+            strip_emitted("the quick brown fox", " brown fox jumps over"),
+            Some("jumps over".to_string())
+        );
+        assert_eq!(
+            strip_emitted("今日はいい天気ですね", "天気ですね明日は雨"),
+            Some("明日は雨".to_string())
+        );
+    }
+
+    #[test]
+    fn strip_emitted_drops_fully_emitted_text() {
+        assert_eq!(strip_emitted("the quick brown fox", " brown fox"), None);
+        assert_eq!(strip_emitted("the quick brown fox", "  "), None);
+    }
+
+    #[test]
+    fn strip_emitted_keeps_text_without_overlap() {
+        assert_eq!(
+            strip_emitted("hello", " world peace"),
+            Some("world peace".to_string())
+        );
+        // 1文字だけ重なっても偶然のことが多いので取り除かない
+        assert_eq!(strip_emitted("abc", "c def"), Some("c def".to_string()));
+    }
+
+    // 送ったペイロードの型の名前だけを記録する
+    #[derive(Default)]
+    struct RecordingSink {
+        sent: Mutex<Vec<&'static str>>,
+    }
+
+    impl SegmentSink for RecordingSink {
+        fn send<S: serde::Serialize + Clone>(&self, _: &str, _: S) {
+            self.sent.lock().unwrap().push(std::any::type_name::<S>());
+        }
+
+        fn push(&self, _: Data) {
+            panic!("segments of a range run must not go to the STORE");
+        }
+    }
+
+    // セグメントをcollectedにためる、範囲の文字起こしと同じ設定
+    fn recording_data(
+        collected: &Arc<Mutex<Vec<Data>>>,
+        time_base_ms: i64,
+        skip_before_ms: i64,
+    ) -> CallbackData<RecordingSink> {
+        CallbackData {
+            app: RecordingSink::default(),
+            run_id: 1,
+            cancel: Arc::new(AtomicBool::new(false)),
+            progress_interval: 0,
+            started: Instant::now(),
+            last_progress: AtomicI32::new(-1),
+            no_speech_thold: 0.6,
+            word_timestamps: false,
+            max_segment_chars: None,
+            suppress_tokens: Vec::new(),
+            time_base_ms,
+            skip_before_ms,
+            collected: Some(collected.clone()),
+            repeat_filter: RepeatFilter::Off,
+            dropped: AtomicUsize::new(0),
+            marked: AtomicUsize::new(0),
+            segments: AtomicUsize::new(0),
+            metrics: false,
+            tokens: AtomicUsize::new(0),
+            last_metrics_ms: AtomicU64::new(0),
+        }
+    }
+
+    // 不正なUTF-8のセグメントを何度はさんでも、警告を送るだけで後のセグメントはそのまま届く
+    #[test]
+    fn invalid_segment_text_does_not_stop_later_segments() {
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let data = recording_data(&collected, 0, 0);
+        // 単独の不正なバイト、途中で切れた「あ」、長すぎる表現の「/」
+        let invalid: [&[u8]; 3] = [&[0xff], &[0xe3, 0x81], &[b'a', 0xc0, 0xaf]];
+        let n = 1000;
+        let mut expected = Vec::new();
+        for i in 0..n {
+            let t0_ms = i as i64 * 1000;
+            deliver_segment(
+                &data,
+                RawSegment {
+                    text: invalid[i % invalid.len()],
+                    t0_ms,
+                    t1_ms: t0_ms + 400,
+                    no_speech: false,
+                    words: Vec::new(),
+                    confidence: 0.5,
+                    speaker_turn: false,
+                },
+            );
+            let text = format!("segment {}", i);
+            deliver_segment(
+                &data,
+                RawSegment {
+                    text: text.as_bytes(),
+                    t0_ms: t0_ms + 500,
+                    t1_ms: t0_ms + 900,
+                    no_speech: false,
+                    words: Vec::new(),
+                    confidence: 0.5,
+                    speaker_turn: false,
+                },
+            );
+            expected.push(Data::new(
+                t0_ms + 500,
+                t0_ms + 900,
+                text,
+                Vec::new(),
+                0.5,
+                false,
+                false,
+            ));
+        }
+        assert_eq!(data.segments.load(Ordering::SeqCst), n);
+        assert_eq!(
+            format!("{:?}", collected.lock().unwrap()),
+            format!("{:?}", expected)
+        );
+        let sent = data.app.sent.lock().unwrap();
+        let count = |name: &str| sent.iter().filter(|sent| sent.ends_with(name)).count();
+        assert_eq!(count("::WhisperPayload"), n);
+        assert_eq!(count("::WhisperSegmentPayload"), n);
+    }
+
+    // 1秒ごとに値を変えた音声を半端な大きさに分けて送り、区間の中の1秒を1つのセグメントとみなして文字起こしする。
+    // 重なり部分で出し直したセグメントは捨てられ、どの1秒もちょうど1回、正しい時刻で出る
+    #[test]
+    fn stream_windows_skip_segments_emitted_in_overlap() {
+        let samples_per_sec = audio::WHISPER_SAMPLE_RATE as usize;
+        let seconds = 23;
+        let audio: Vec<f32> = (0..seconds * samples_per_sec)
+            .map(|i| (i / samples_per_sec) as f32 / 100.)
+            .collect();
+        let (tx, rx) = mpsc::channel();
+        for chunk in audio.chunks(4_800) {
+            tx.send(chunk.to_vec()).unwrap();
+        }
+        drop(tx);
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let mut windows = Vec::new();
+        let total = stream_windows(rx, &AtomicBool::new(false), |audio_data, window, _| {
+            windows.push((window.time_base_ms, window.skip_before_ms, window.first));
+            let data = recording_data(&collected, window.time_base_ms, window.skip_before_ms);
+            for local in 0..audio_data.len() / samples_per_sec {
+                let second = (audio_data[local * samples_per_sec] * 100.).round() as i64;
+                let text = format!("second {}", second);
+                deliver_segment(
+                    &data,
+                    RawSegment {
+                        text: text.as_bytes(),
+                        t0_ms: local as i64 * 1000,
+                        t1_ms: (local as i64 + 1) * 1000,
+                        no_speech: false,
+                        words: Vec::new(),
+                        confidence: 1.,
+                        speaker_turn: false,
+                    },
+                );
+            }
+            Ok(RunStats::default())
+        })
+        .unwrap();
+        assert_eq!(
+            windows,
+            vec![
+                (0, 0, true),
+                (5_000, 5_000, false),
+                (10_000, 5_000, false),
+                (15_000, 5_000, false),
+            ]
+        );
+        let expected: Vec<Data> = (0..seconds as i64)
+            .map(|second| {
+                Data::new(
+                    second * 1000,
+                    (second + 1) * 1000,
+                    format!("second {}", second),
+                    Vec::new(),
+                    1.,
+                    false,
+                    false,
+                )
+            })
+            .collect();
+        assert_eq!(
+            format!("{:?}", collected.lock().unwrap()),
+            format!("{:?}", expected)
+        );
+        assert_eq!(total.audio_duration_ms, seconds as u64 * 1000);
+    }
+}
@@ -0,0 +1,187 @@
+use libc::{c_char, c_void};
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+use tauri::Manager;
+
+const MAX_RECENT_LINES: usize = 1000;
+// ファイルがこの大きさを超えたら.1へ移して新しく書き始める
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+// 起動時などにまとめて出る行でフロントエンドを詰まらせないよう、1秒あたりの通知数を抑える
+const MAX_EVENTS_PER_SEC: usize = 20;
+
+#[derive(Clone, serde::Serialize, Debug)]
+pub struct LogLine {
+    level: &'static str,
+    text: String,
+}
+
+struct WhisperLog {
+    app: Option<tauri::AppHandle>,
+    file: Option<(PathBuf, File)>,
+    written: u64,
+    // whisper.cppは1行を何回かに分けて渡すことがあるので、改行が来るまでためておく
+    pending: String,
+    lines: VecDeque<LogLine>,
+    window_start: Instant,
+    sent_in_window: usize,
+    // モデルの読み込み時に出る"using CUDA backend"などから拾った名前
+    backend: Option<String>,
+}
+
+static WHISPER_LOG: Lazy<Mutex<WhisperLog>> = Lazy::new(|| {
+    Mutex::new(WhisperLog {
+        app: None,
+        file: None,
+        written: 0,
+        pending: String::new(),
+        lines: VecDeque::new(),
+        window_start: Instant::now(),
+        sent_in_window: 0,
+        backend: None,
+    })
+});
+
+static INSTALL: Once = Once::new();
+
+pub fn log_path(app: &tauri::AppHandle) -> Option<PathBuf> {
+    tauri::api::path::app_data_dir(&app.config()).map(|dir| dir.join("pothook/logs/whisper.log"))
+}
+
+// whisper.cppのログの出力先を差し替える。ログはワーカースレッドからも呼ばれる
+pub fn install(app: tauri::AppHandle) {
+    INSTALL.call_once(|| {
+        if let Ok(mut log) = WHISPER_LOG.lock() {
+            if let Some(path) = log_path(&app) {
+                if let Some(dir) = path.parent() {
+                    let _ = fs::create_dir_all(dir);
+                }
+                if let Ok(file) = OpenOptions::new().create(true).append(true).open(&path) {
+                    log.written = file.metadata().map_or(0, |meta| meta.len());
+                    log.file = Some((path, file));
+                }
+            }
+            log.app = Some(app);
+        }
+        unsafe { whisper_rs_sys::whisper_log_set(Some(log_callback), std::ptr::null_mut()) };
+    });
+}
+
+unsafe extern "C" fn log_callback(
+    level: whisper_rs_sys::ggml_log_level,
+    text: *const c_char,
+    _: *mut c_void,
+) {
+    if text.is_null() {
+        return;
+    }
+    let text = CStr::from_ptr(text).to_string_lossy();
+    let Ok(mut log) = WHISPER_LOG.lock() else {
+        return;
+    };
+    log.pending.push_str(&text);
+    while let Some(end) = log.pending.find('\n') {
+        let line: String = log.pending.drain(..=end).collect();
+        let line = line.trim_end();
+        if !line.is_empty() {
+            log.push(level_name(level), line.to_string());
+        }
+    }
+}
+
+fn level_name(level: whisper_rs_sys::ggml_log_level) -> &'static str {
+    match level {
+        whisper_rs_sys::ggml_log_level_GGML_LOG_LEVEL_ERROR => "error",
+        whisper_rs_sys::ggml_log_level_GGML_LOG_LEVEL_WARN => "warn",
+        whisper_rs_sys::ggml_log_level_GGML_LOG_LEVEL_INFO => "info",
+        _ => "debug",
+    }
+}
+
+impl WhisperLog {
+    fn push(&mut self, level: &'static str, text: String) {
+        if let Some(backend) = parse_backend(&text) {
+            self.backend = Some(backend.to_string());
+        }
+        let line = LogLine { level, text };
+        self.write(&line);
+        self.emit(&line);
+        if self.lines.len() >= MAX_RECENT_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    fn write(&mut self, line: &LogLine) {
+        if self.written > MAX_LOG_BYTES {
+            self.rotate();
+        }
+        let Some((_, file)) = self.file.as_mut() else {
+            return;
+        };
+        let text = format!("[{}] {}\n", line.level, line.text);
+        if file.write_all(text.as_bytes()).is_ok() {
+            self.written += text.len() as u64;
+        }
+    }
+
+    fn rotate(&mut self) {
+        let Some((path, _)) = self.file.take() else {
+            return;
+        };
+        let _ = fs::rename(&path, path.with_extension("log.1"));
+        if let Ok(file) = File::create(&path) {
+            self.file = Some((path, file));
+        }
+        self.written = 0;
+    }
+
+    fn emit(&mut self, line: &LogLine) {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.sent_in_window = 0;
+        }
+        if self.sent_in_window >= MAX_EVENTS_PER_SEC {
+            return;
+        }
+        self.sent_in_window += 1;
+        if let Some(app) = &self.app {
+            let _ = app.emit_all("whisper-log", line.clone());
+        }
+    }
+}
+
+// "whisper_backend_init: using Metal backend"のような行からバックエンドの名前を取り出す
+fn parse_backend(text: &str) -> Option<&str> {
+    let rest = &text[text.find("using ")? + "using ".len()..];
+    let name = rest.strip_suffix(" backend")?;
+    (!name.is_empty() && !name.contains(' ')).then_some(name)
+}
+
+// 前回取り出してから記録されたバックエンドの名前を返す。
+// モデルを読み込む前に一度呼んで古い値を消しておく
+pub fn take_backend() -> Option<String> {
+    WHISPER_LOG
+        .lock()
+        .ok()
+        .and_then(|mut log| log.backend.take())
+}
+
+// 新しい行ほど後ろになるよう、最後のn行を返す
+pub fn recent(n: usize) -> Vec<LogLine> {
+    WHISPER_LOG.lock().map_or_else(
+        |_| Vec::new(),
+        |log| {
+            log.lines
+                .iter()
+                .skip(log.lines.len().saturating_sub(n))
+                .cloned()
+                .collect()
+        },
+    )
+}